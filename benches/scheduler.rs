@@ -0,0 +1,52 @@
+//! Compares the shared queue against the work-stealing scheduler on many small jobs.
+//! Run with `cargo bench --bench scheduler`
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use job_system::system::{
+    job_system::{JobSystem, JobSystemBuilder},
+    scheduler::Scheduler,
+};
+
+const JOBS: u64 = 200_000;
+const ROUNDS: u32 = 5;
+
+/// A job small enough that handing it to a worker costs more than running it
+fn small_job(x: u64) -> u64 {
+    (0..64).fold(x, |acc, i| black_box(acc.wrapping_mul(31).wrapping_add(i)))
+}
+
+fn run(scheduler: Scheduler, workers: usize) -> Duration {
+    let mut system: JobSystem<u64, u64> = JobSystemBuilder::new()
+        .scheduler(scheduler)
+        .workers(workers)
+        .build();
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..JOBS).map(|x| system.send_job(x, small_job)).collect();
+    for handle in handles {
+        black_box(handle.get().unwrap());
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let workers = num_cpus::get();
+    println!(
+        "{} jobs on {} workers, best of {} rounds",
+        JOBS, workers, ROUNDS
+    );
+
+    for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+        let best = (0..ROUNDS).map(|_| run(scheduler, workers)).min().unwrap();
+        println!(
+            "{:?}: {:?} ({:.0} jobs/s)",
+            scheduler,
+            best,
+            JOBS as f64 / best.as_secs_f64()
+        );
+    }
+}
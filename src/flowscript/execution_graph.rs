@@ -13,7 +13,7 @@ use std::{
     sync::Arc,
 };
 
-use crate::system::job_system::JobSystem;
+use crate::system::{job_context, job_handle::JobError, job_system::JobSystem};
 
 use super::{
     tokenizer::{BrState, Key, Token},
@@ -31,6 +31,9 @@ struct ExecuteArgs(Value, NodeIndex, Arc<DiGraph<ProcessNode, usize>>);
 
 impl ProcessNode {
     pub fn execute(args: ExecuteArgs) -> Result<Value, Box<dyn Error + Send + Sync>> {
+        if job_context::is_cancelled() {
+            return Err(JobError::Cancelled.into());
+        }
         let index = args.1;
         let graph = args.2;
         let attr_json = serde_json::from_str(match graph[index].attributes.get(&Key::Data) {
@@ -41,6 +44,8 @@ impl ProcessNode {
         let x = super::util::merge_json(&args.0, &attr_json);
 
         let pnode = &graph[index];
+        let _span =
+            tracing::info_span!("node", name = %pnode.name, index = index.index()).entered();
         match crate::system::job_system::ffi::map_job_identifier(&pnode.name) {
             Some(f) => {
                 let y = f(x);
@@ -57,9 +62,7 @@ impl ProcessNode {
                 // Sort edges based on their weight (order)
                 outgoing_edges.sort_unstable_by(|a, b| a.weight().cmp(b.weight()));
 
-                let next_idx = outgoing_edges
-                    .get(status as usize)
-                    .and_then(|e| Some(e.target()));
+                let next_idx = outgoing_edges.get(status as usize).map(|e| e.target());
                 if let Some(next_idx) = next_idx {
                     let args = ExecuteArgs(res.to_owned().into(), next_idx, graph.to_owned());
                     Self::execute(args)
@@ -73,7 +76,7 @@ impl ProcessNode {
 }
 
 #[derive(Debug)]
-pub(crate) struct ExecutionGraph {
+pub struct ExecutionGraph {
     name: Option<String>,
     graph: DiGraph<ProcessNode, usize>, // Empty tuple `()` as edge weight
     node_indices: HashMap<String, NodeIndex>, // For quick node lookup
@@ -153,6 +156,7 @@ impl ExecutionGraph {
         });
 
         let temp_graph = Arc::new(self.graph.to_owned());
+        let _span = tracing::info_span!("flowscript", name = ?self.name).entered();
 
         let root_handles: Vec<_> = roots
             .map(|i| {
@@ -163,7 +167,10 @@ impl ExecutionGraph {
             })
             .collect();
 
-        root_handles.into_iter().map(|h| h.get()).collect()
+        root_handles
+            .into_iter()
+            .map(|h| h.get().unwrap_or_else(|e| Err(e.into())))
+            .collect()
     }
 
     fn parse_node_attributes(
@@ -183,7 +190,7 @@ impl ExecutionGraph {
                         .next()
                         .ok_or(format!("Expected text after '=' in {:?}", attrs_tokens))?
                     {
-                        attributes.insert(key.clone(), value.clone());
+                        attributes.insert(*key, value.clone());
                     }
                 } else {
                     return Err(format!("Expected '=' after key in {:?}", attrs_tokens).into());
@@ -221,12 +228,11 @@ impl ExecutionGraph {
         tokens
             .next()
             .ok_or("Expect digraph token at beginning of parse")?;
-        let graph_name;
-        match tokens.next().ok_or("Expected token after digraph")? {
-            Token::Brace(BrState::Open) => graph_name = None,
-            Token::Text(name) => graph_name = Some(name),
+        let graph_name = match tokens.next().ok_or("Expected token after digraph")? {
+            Token::Brace(BrState::Open) => None,
+            Token::Text(name) => Some(name),
             t => return Err(format!("Unexpected token after digraph: {:?}", t).into()),
-        }
+        };
 
         let mut graph = Self::new(graph_name, num_cpus::get());
 
@@ -1,6 +1,6 @@
 use std::iter::Peekable;
 
-use serde_json::{Map, Value};
+use serde_json::Value;
 
 pub(crate) fn extract_until<T, I, P>(iter: &mut Peekable<I>, predicate: P) -> Vec<T>
 where
@@ -63,8 +63,8 @@ impl<T> SpliteratorAdapter for T where T: Iterator {}
 pub(crate) fn merge_json(a: &Value, b: &Value) -> Value {
     a.as_object()
         .into_iter()
-        .chain(b.as_object().into_iter())
-        .flat_map(|x| x)
+        .chain(b.as_object())
+        .flatten()
         .map(|(k, v)| (k.clone(), v.clone()))
         .collect()
 }
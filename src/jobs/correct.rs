@@ -2,6 +2,8 @@ use openai_api_rust::{chat::*, *};
 use serde_json::{json, Value};
 use std::error::Error;
 
+use crate::system::job_context;
+
 fn fix_linker_err(llm: &OpenAI, error: &Value, prompt: &str) -> Result<Value, Box<dyn Error>> {
     let linker_msg = error["message"].as_str().ok_or("message not found")?;
 
@@ -99,24 +101,39 @@ pub fn correct(input: Value) -> Value {
         }
     };
 
+    let errors: Vec<&Value> = compiler_errors
+        .iter()
+        .flat_map(|f| f["errors"].as_array())
+        .flatten()
+        .collect();
+    // One LLM call for the linker error, plus one per compiler error
+    let steps = errors.len() + 1;
+
     let linker_fixes = match fix_linker_err(&llm, &input["linker"], linker_err_prompt) {
         Ok(fixes) => fixes,
         Err(e) => return json!({"result" : {"message" : e.to_string()}, "status" : 1}),
     };
-
-    let compiler_fixes: Vec<Value> = compiler_errors
-        .iter()
-        .flat_map(|f| f["errors"].as_array())
-        .flatten()
-        .map(|e| fix_compile_err(&llm, e, compiler_err_prompt))
+    job_context::report_progress(100.0 / steps as f64);
+
+    let compiler_fixes: Vec<Value> = errors
+        .into_iter()
+        .enumerate()
+        // each fix is a separate LLM call, so stop issuing them once the job is cancelled
+        .take_while(|_| !job_context::is_cancelled())
+        .map(|(i, e)| {
+            let fix = fix_compile_err(&llm, e, compiler_err_prompt);
+            job_context::report_progress(100.0 * (i + 2) as f64 / steps as f64);
+            fix
+        })
         .filter_map(|f| {
             if let Err(e) = f {
-                eprintln!("Parsing error: {}", e);
+                tracing::warn!("Parsing error: {}", e);
                 None
             } else {
                 f.ok()
             }
         })
+        .inspect(|fix| job_context::emit(fix.clone()))
         .collect();
 
     json!({"result" :{"compiler_fixes" : compiler_fixes, "linker_fixes": linker_fixes}, "status" : 0})
@@ -1,7 +1,7 @@
 use serde_json::{json, Value};
 
 pub fn display_error(input: Value) -> Value {
-    eprint!("Error: ");
+    tracing::error!(%input, "error message");
     println!("{}", input);
     json!({"result": {}, "status": 0})
 }
@@ -1,17 +1,19 @@
 use std::{
     fs::File,
-    io::{BufRead, BufReader},
+    io::{self, BufRead, BufReader, Seek},
 };
 
 use serde_json::{json, Value};
 
-fn get_context(reader: &mut BufReader<File>, start: u64, len: usize) -> String {
-    reader
+fn get_context(reader: &mut BufReader<File>, start: u64, len: usize) -> io::Result<String> {
+    // The reader is shared by every error in the file, so it starts over from the top each time
+    reader.rewind()?;
+    Ok(reader
         .lines()
         .skip(start as usize)
         .take(len)
         .flat_map(|line_result| line_result.ok())
-        .collect()
+        .collect())
 }
 
 fn replace_entries(filename: &str, error_array: &mut Vec<Value>) -> Result<(), Value> {
@@ -19,7 +21,10 @@ fn replace_entries(filename: &str, error_array: &mut Vec<Value>) -> Result<(), V
         let mut reader = BufReader::new(file);
         for error in error_array {
             if let Some(line) = error["line"].as_u64() {
-                let context = get_context(&mut reader, 0.min(line - 2), 5);
+                // Lines are numbered from 1, and the context starts 2 lines above the error
+                let context = get_context(&mut reader, line.saturating_sub(3), 5).map_err(
+                    |e| json!({"error" : format!("unable to read {}: {}", filename, e)}),
+                )?;
                 error["context"] = Value::String(context);
             } else {
                 return Err(json!({"error" : "line must exist and be an Integer type" }));
@@ -1,13 +1,45 @@
-use std::process::Command;
+use std::{
+    io::{self, BufRead, BufReader},
+    process::{Command, Stdio},
+};
 
 use serde_json::{json, Value};
 
+use crate::system::job_context;
+
+/// Runs `make` on `target`, emitting every line of compiler output as progress while the build runs
+fn run_make(target: &str) -> io::Result<String> {
+    let mut child = Command::new("make")
+        .arg(target)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut clang_output = String::new();
+    if let Some(stderr) = child.stderr.take() {
+        let mut reader = BufReader::new(stderr);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            let text = String::from_utf8_lossy(&line);
+            job_context::emit(json!({"line": text.trim_end()}));
+            clang_output.push_str(&text);
+            line.clear();
+            if job_context::is_cancelled() {
+                child.kill()?;
+                break;
+            }
+        }
+    }
+    child.wait()?;
+    Ok(clang_output)
+}
+
 /// Parser, which will launch the make target specified by the the `target` key in the json input
 pub(crate) fn output(input: Value) -> Value {
     if let Some(target) = input["target"].as_str() {
-        match Command::new("make").arg(target).output() {
-            Ok(c) => {
-                json!({"result": {"clang_output": String::from_utf8_lossy(&c.stderr)}, "status": 0})
+        match run_make(target) {
+            Ok(clang_output) => {
+                json!({"result": {"clang_output": clang_output}, "status": 0})
             }
             Err(e) => {
                 json!({"result": {"message": e.to_string()}, "status": 1})
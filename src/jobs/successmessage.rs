@@ -1,7 +1,7 @@
 use serde_json::{json, Value};
 
 pub fn print_success(input: Value) -> Value {
-    eprintln!("Success: ");
+    tracing::info!(%input, "success message");
     println!("{}", input);
     json!({"result": {}, "status": 0})
 }
@@ -1,3 +1,4 @@
+pub mod flowscript;
 pub mod jobs;
 pub mod system;
 
@@ -1,25 +1,28 @@
-mod flowscript;
-mod jobs;
-mod system;
-
 use clap::Parser;
-use serde_json::{json, Value};
 
-use crate::{
-    flowscript::execution_graph::ExecutionGraph, flowscript::tokenizer::TokenizerAdapter,
-    system::job_system::JobSystem,
+use job_system::{
+    flowscript::execution_graph::ExecutionGraph,
+    flowscript::tokenizer::TokenizerAdapter,
+    system::{job_handle::join_all, job_system::JobSystem},
 };
+use tracing_subscriber::EnvFilter;
+
 use std::{
     error::Error,
     fmt::Display,
     fs::File,
     io::{self, Read},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 #[derive(Parser)]
 #[clap(version = "1.0", author = "Pravin Ramana")]
 struct Args {
     files: Vec<String>,
+    /// Re-run the graph every given number of minutes instead of exiting after the first run
+    #[clap(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+    watch: Option<u64>,
 }
 
 fn print_if_err<T, E>(r: Result<T, E>) -> Result<T, E>
@@ -27,12 +30,19 @@ where
     E: Sized + Display,
 {
     if let Err(ref e) = r {
-        eprintln!("{}", e)
+        tracing::error!("{}", e)
     }
     r
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
+    // Logs go to stderr, filtered through RUST_LOG, e.g. RUST_LOG=job_system=debug to see every job change status
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_writer(io::stderr)
+        .init();
     main_cli()
 }
 
@@ -45,38 +55,49 @@ fn main_cli() -> Result<(), Box<dyn Error>> {
     let code_files = args
         .files
         .into_iter()
-        .map(|name| File::open(name))
-        .flat_map(|r| print_if_err(r))
+        .map(File::open)
+        .flat_map(print_if_err)
         .map(|f| -> io::Result<String> {
             let mut f = f;
             let mut data = String::new();
             f.read_to_string(&mut data)?;
             Ok(data)
         })
-        .flat_map(|r| print_if_err(r));
+        .flat_map(print_if_err);
 
-    let graph_handles: Vec<_> = code_files
-        .into_iter()
-        .map(|code| {
-            parser_system.send_job(code, |code| {
-                let mut tokens = code
-                    .into_bytes()
-                    .into_iter()
-                    .map(|b| b as char)
-                    .tokens()
-                    .peekable();
-                ExecutionGraph::from_tokens(&mut tokens)
-            })
-        })
-        .collect();
+    let graph_handles = parser_system.map(code_files, |code| {
+        let mut tokens = code
+            .into_bytes()
+            .into_iter()
+            .map(|b| b as char)
+            .tokens()
+            .peekable();
+        ExecutionGraph::from_tokens(&mut tokens)
+    });
 
-    let mut merged_graph: ExecutionGraph = graph_handles
+    let mut merged_graph: ExecutionGraph = join_all(graph_handles)
         .into_iter()
-        .map(|h| h.get())
-        .flat_map(|r| print_if_err(r))
+        .map(|r| r.unwrap_or_else(|e| Err(e.into())))
+        .flat_map(print_if_err)
         .sum();
 
     let _res = merged_graph.execute_all();
     // dbg!(res);
+
+    if let Some(minutes) = args.watch {
+        let mut watcher = JobSystem::new();
+        watcher.add_worker();
+        let graph = Arc::new(Mutex::new(merged_graph));
+        let runs = watcher.send_job_every(
+            Duration::from_secs(minutes * 60),
+            graph,
+            |graph: Arc<Mutex<ExecutionGraph>>| {
+                graph.lock().unwrap().execute_all();
+            },
+        );
+        for run in runs {
+            let _ = print_if_err(run.get());
+        }
+    }
     Ok(())
 }
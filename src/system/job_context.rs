@@ -0,0 +1,88 @@
+//! Information about the job running on the current worker thread
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde_json::Value;
+
+/// Intermediate values beyond this many are dropped, oldest first, if nobody reads them
+const MAX_PROGRESS_UPDATES: usize = 1024;
+
+/// Progress reported by a job, as read through `JobHandle::take_progress`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// Last percentage reported by the job, between 0 and 100
+    pub percent: Option<f64>,
+    /// Intermediate values emitted by the job since the progress was last taken
+    pub updates: Vec<Value>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ProgressSink {
+    percent: Option<f64>,
+    updates: VecDeque<Value>,
+}
+
+impl ProgressSink {
+    pub(crate) fn take(&mut self) -> Progress {
+        Progress {
+            percent: self.percent,
+            updates: self.updates.drain(..).collect(),
+        }
+    }
+}
+
+/// The parts of a job handle a running job may reach through this module
+#[derive(Clone, Debug)]
+pub(crate) struct JobContext {
+    pub(crate) cancelled: Arc<AtomicBool>,
+    pub(crate) progress: Arc<Mutex<ProgressSink>>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<JobContext>> = const { RefCell::new(None) };
+}
+
+/// Installs the context of the job that is about to run on this thread
+pub(crate) fn enter(context: JobContext) {
+    CONTEXT.with(|c| *c.borrow_mut() = Some(context));
+}
+
+/// Clears the context once the job has returned
+pub(crate) fn exit() {
+    CONTEXT.with(|c| c.borrow_mut().take());
+}
+
+fn with_context<R>(f: impl FnOnce(&JobContext) -> R) -> Option<R> {
+    CONTEXT.with(|c| c.borrow().as_ref().map(f))
+}
+
+/// Returns true if the job running on this thread was asked to cancel. Long running jobs should check this between
+/// units of work and return early. Always false outside of a job
+pub fn is_cancelled() -> bool {
+    with_context(|context| context.cancelled.load(Ordering::Relaxed)).unwrap_or(false)
+}
+
+/// Reports how far along the running job is, as a percentage. Does nothing outside of a job
+pub fn report_progress(percent: f64) {
+    with_context(|context| {
+        context.progress.lock().unwrap().percent = Some(percent.clamp(0.0, 100.0));
+    });
+}
+
+/// Emits an intermediate value of the running job, such as a partial result. Does nothing outside of a job
+pub fn emit(value: Value) {
+    with_context(|context| {
+        let mut progress = context.progress.lock().unwrap();
+        if progress.updates.len() == MAX_PROGRESS_UPDATES {
+            progress.updates.pop_front();
+        }
+        progress.updates.push_back(value);
+    });
+}
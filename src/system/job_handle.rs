@@ -1,41 +1,457 @@
-use std::sync::{Arc, Condvar, Mutex};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Debug, Display},
+    future::{Future, IntoFuture},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex, OnceLock, Weak,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
 
-#[derive(Clone, Debug)]
+use serde::{Deserialize, Serialize};
+use tracing::{field, Span};
+
+use super::{
+    job_context::{JobContext, Progress, ProgressSink},
+    metrics::{Metrics, UNTYPED},
+    worker::{RunningJob, WorkerQueue},
+};
+
+/// A boxed job function, which may capture its own state
+pub(crate) type JobFn<X, Y> = Box<dyn FnOnce(X) -> Y + Send>;
+
+/// Called with the outcome of a job as soon as it finishes
+pub(crate) type CompletionFn<Y> = Box<dyn FnOnce(&Result<Y, JobError>) + Send>;
+
+/// Called once a job has finished and none of its attempts is still running
+pub(crate) type SettledFn = Box<dyn FnOnce() + Send>;
+
+/// Looks at the outcome of an attempt and, if another attempt should be made, re-arms and requeues the job.
+/// Returns whether it did so
+pub(crate) type RetryFn<X, Y> =
+    Box<dyn FnMut(&Arc<HandleInner<X, Y>>, &Result<Y, JobError>) -> bool + Send>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Status {
+    /// Waiting on the jobs it depends on, or for the time it is scheduled at, before it is queued
+    Pending,
     Queued,
     Running,
     Completed,
+    Cancelled,
+    Panicked,
+    /// The job did not finish before its deadline
+    TimedOut,
+    /// The job was journaled by an earlier run of the process, but could not be sent again when it was replayed
+    ReplayFailed,
 }
-#[derive(Debug)]
+
+/// The reason a job did not produce a result
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobError {
+    Cancelled,
+    /// The job function panicked, carrying the panic message
+    Panicked(String),
+    /// One of the jobs this job depends on did not complete, so it never ran
+    DependencyFailed,
+    /// The deadline of the job passed before it finished
+    TimedOut,
+    /// The journaled job could not be sent again after a restart, carrying the reason. It never ran
+    ReplayFailed(String),
+}
+
+impl Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Cancelled => write!(f, "job was cancelled"),
+            JobError::Panicked(message) => write!(f, "job panicked: {}", message),
+            JobError::DependencyFailed => write!(f, "a dependency of the job did not complete"),
+            JobError::TimedOut => write!(f, "job timed out"),
+            JobError::ReplayFailed(message) => write!(f, "job could not be replayed: {}", message),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+/// Source of the ids that identify jobs in tracing output, unique across all systems
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(0);
+
 pub(crate) struct HandleInner<X, Y> {
+    pub(crate) id: u64,
+    /// Entered by the thread running the job, so everything the job logs is attributed to it
+    pub(crate) span: Span,
     pub(crate) x: Mutex<Option<X>>,
-    pub(crate) f: fn(X) -> Y,
+    pub(crate) f: Mutex<Option<JobFn<X, Y>>>,
     pub(crate) status: Mutex<Status>,
-    pub(crate) result: Mutex<Option<Y>>,
+    pub(crate) result: Mutex<Option<Result<Y, JobError>>>,
     pub(crate) available: Condvar,
+    /// Set by the first call to `complete`. A job that was abandoned on shutdown is completed again once it returns
+    finished: AtomicBool,
+    /// Set while the function of an attempt runs, which outlasts the job if it was left behind by its deadline or
+    /// abandoned on shutdown
+    running: AtomicBool,
+    on_settled: Mutex<Vec<SettledFn>>,
+    /// Set while the job is parked by the limit on its type, during which it still counts as queued against the
+    /// capacity of the system. Only changed with `status` locked
+    parked: AtomicBool,
+    /// Cooperative cancellation token, which is visible to the running job through `job_context`
+    pub(crate) cancelled: Arc<AtomicBool>,
+    progress: Arc<Mutex<ProgressSink>>,
+    on_complete: Mutex<Vec<CompletionFn<Y>>>,
+    /// The task awaiting the JobHandle, if it is being polled as a Future
+    waker: Mutex<Option<Waker>>,
+    /// Number of times the job has been started
+    attempts: AtomicU32,
+    pub(crate) retry: Mutex<Option<RetryFn<X, Y>>>,
+    /// Set once, before the job is queued
+    pub(crate) deadline: OnceLock<Instant>,
+    /// The type the job is counted under in the metrics of its system
+    pub(crate) job_type: OnceLock<String>,
+    metrics: Arc<Metrics>,
+    /// When the job last entered the queued or running state
+    since: Mutex<Instant>,
+}
+
+impl<X, Y> HandleInner<X, Y> {
+    /// The context installed on the worker thread while this job runs
+    pub(crate) fn context(&self) -> JobContext {
+        JobContext {
+            cancelled: self.cancelled.clone(),
+            progress: self.progress.clone(),
+        }
+    }
+
+    fn job_type(&self) -> &str {
+        self.job_type.get().map_or(UNTYPED, String::as_str)
+    }
+
+    pub(crate) fn set_job_type(&self, job_type: String) {
+        self.span.record("type", job_type.as_str());
+        let _ = self.job_type.set(job_type);
+    }
+
+    /// Moves a job that was just created into the pending state, where it waits until it is released
+    pub(crate) fn hold(&self) {
+        let mut status = self.status.lock().unwrap();
+        if *status == Status::Queued {
+            *status = Status::Pending;
+            self.metrics.dequeued();
+            tracing::debug!(parent: &self.span, "job pending");
+        }
+    }
+
+    /// Same as `hold` for a queued job that has to wait for a slot of its type, except that it keeps its place in
+    /// the capacity of the system, so jobs held back this way cannot pile up past it
+    pub(crate) fn park(&self) {
+        let mut status = self.status.lock().unwrap();
+        if *status == Status::Queued {
+            *status = Status::Pending;
+            self.parked.store(true, Ordering::Relaxed);
+            tracing::debug!(parent: &self.span, "job pending");
+        }
+    }
+
+    /// Moves a pending job into the queued state. Returns false if it was resolved in the meantime
+    pub(crate) fn release(&self) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if *status != Status::Pending {
+            return false;
+        }
+        *status = Status::Queued;
+        *self.since.lock().unwrap() = Instant::now();
+        if !self.parked.swap(false, Ordering::Relaxed) {
+            self.metrics.enqueued();
+        }
+        tracing::debug!(parent: &self.span, "job queued");
+        true
+    }
+
+    /// Resolves a job that never started with `error`. Returns false if the job was already running or finished
+    pub(crate) fn resolve_unstarted(&self, error: JobError) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if !matches!(*status, Status::Pending | Status::Queued) {
+            return false;
+        }
+        if *status == Status::Queued || self.parked.swap(false, Ordering::Relaxed) {
+            self.metrics.dequeued();
+        }
+        // Leaving the queued state first keeps a worker that already received the job from starting it
+        *status = Status::Cancelled;
+        drop(status);
+        self.x.lock().unwrap().take();
+        self.f.lock().unwrap().take();
+        self.complete(Err(error));
+        true
+    }
+
+    /// Puts a running job back into the queued state with a fresh input and function, for another attempt.
+    /// Returns false if the job was cancelled while it ran
+    pub(crate) fn rearm(&self, x: X, f: JobFn<X, Y>) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if *status != Status::Running || self.cancelled.load(Ordering::Relaxed) {
+            return false;
+        }
+        *self.x.lock().unwrap() = Some(x);
+        *self.f.lock().unwrap() = Some(f);
+        *status = Status::Queued;
+        let mut since = self.since.lock().unwrap();
+        let now = Instant::now();
+        self.metrics.stopped(self.job_type(), now - *since);
+        self.metrics.enqueued();
+        *since = now;
+        tracing::debug!(parent: &self.span, "job queued for another attempt");
+        true
+    }
+
+    /// Hands the outcome of an attempt to the retry policy of the job, if it has one.
+    /// Returns true if the job was requeued, in which case it must not be completed
+    pub(crate) fn retry(self: &Arc<Self>, result: &Result<Y, JobError>) -> bool {
+        match self.retry.lock().unwrap().as_mut() {
+            Some(retry) => retry(self, result),
+            None => false,
+        }
+    }
+
+    /// Resolves a job that never started with the outcome an earlier run of the system recorded for it. Unlike
+    /// `complete`, it is not counted in the metrics, as the job did not finish in this system
+    pub(crate) fn restore(&self, result: Result<Y, JobError>) {
+        if self.finished.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mut guarded_result = self.result.lock().unwrap();
+        let mut status = self.status.lock().unwrap();
+        if *status == Status::Queued {
+            self.metrics.dequeued();
+        }
+        *status = Self::status_of(&result);
+        self.x.lock().unwrap().take();
+        self.f.lock().unwrap().take();
+        *guarded_result = Some(result);
+    }
+
+    fn status_of(result: &Result<Y, JobError>) -> Status {
+        match result {
+            Ok(_) => Status::Completed,
+            Err(JobError::Cancelled | JobError::DependencyFailed) => Status::Cancelled,
+            Err(JobError::Panicked(_)) => Status::Panicked,
+            Err(JobError::TimedOut) => Status::TimedOut,
+            Err(JobError::ReplayFailed(_)) => Status::ReplayFailed,
+        }
+    }
+
+    /// Stores the outcome of the job and wakes every thread waiting on it
+    pub(crate) fn complete(&self, result: Result<Y, JobError>) {
+        if self.finished.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mut guarded_result = self.result.lock().unwrap();
+        let status = Self::status_of(&result);
+        if *self.status.lock().unwrap() == Status::Running {
+            let run_time = self.since.lock().unwrap().elapsed();
+            self.metrics.stopped(self.job_type(), run_time);
+        }
+        self.metrics.finished(self.job_type(), &result);
+        match &result {
+            Err(
+                error @ (JobError::Panicked(_) | JobError::TimedOut | JobError::ReplayFailed(_)),
+            ) => {
+                tracing::warn!(parent: &self.span, ?status, %error, "job failed")
+            }
+            Err(error) => tracing::debug!(parent: &self.span, ?status, %error, "job failed"),
+            Ok(_) => tracing::debug!(parent: &self.span, ?status, "job finished"),
+        }
+        // Callbacks run before the result is published, so they always see it even if a waiter takes it right after
+        for callback in self.on_complete.lock().unwrap().drain(..) {
+            callback(&result);
+        }
+        *guarded_result = Some(result);
+        *self.status.lock().unwrap() = status;
+        self.available.notify_all();
+        drop(guarded_result);
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+        self.settle();
+    }
+
+    /// Runs the callbacks waiting for the job to settle, if it has finished and no attempt is running. Both sides
+    /// check the other's flag after setting their own, so at least one of them sees the job settled
+    fn settle(&self) {
+        if self.finished.load(Ordering::SeqCst) && !self.running.load(Ordering::SeqCst) {
+            let callbacks: Vec<_> = self.on_settled.lock().unwrap().drain(..).collect();
+            callbacks.into_iter().for_each(|callback| callback());
+        }
+    }
+
+    /// Runs `callback` once the job has finished and its function is no longer running, or right away if that is
+    /// already the case. For resources the job holds while it runs, which a job resolved early must not give up
+    pub(crate) fn on_settled(&self, callback: SettledFn) {
+        self.on_settled.lock().unwrap().push(callback);
+        self.settle();
+    }
+
+    /// Blocks until the result is available or `until` has passed, without taking the result. Returns whether it is
+    /// available
+    pub(crate) fn wait_for_result(&self, until: Option<Instant>) -> bool {
+        let mut data_guard = self.result.lock().unwrap();
+        while data_guard.is_none() {
+            let Some(until) = until else {
+                data_guard = self.available.wait(data_guard).unwrap();
+                continue;
+            };
+            let now = Instant::now();
+            if now >= until {
+                return false;
+            }
+            data_guard = self
+                .available
+                .wait_timeout(data_guard, until - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+
+    /// Runs `callback` once the job finishes, or right away if it already has. The result of a finished job is still
+    /// present as long as its JobHandle has not been consumed
+    pub(crate) fn on_complete(&self, callback: CompletionFn<Y>) {
+        let guarded_result = self.result.lock().unwrap();
+        match guarded_result.as_ref() {
+            Some(result) => callback(result),
+            None => self.on_complete.lock().unwrap().push(callback),
+        }
+    }
+}
+
+impl<X: Send + 'static, Y: Send + 'static> HandleInner<X, Y> {
+    /// Marks the job as running and hands out its input and function, unless the job has left the queued state. The
+    /// function keeps the attempt marked as running until it returns or is dropped unused
+    pub(crate) fn start(self: &Arc<Self>) -> Option<(X, JobFn<X, Y>)> {
+        let mut status = self.status.lock().unwrap();
+        if *status != Status::Queued {
+            return None;
+        }
+        let x = self.x.lock().unwrap().take()?;
+        let f = self.f.lock().unwrap().take()?;
+        *status = Status::Running;
+        let attempt = self.attempts.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut since = self.since.lock().unwrap();
+        let now = Instant::now();
+        let wait = now - *since;
+        self.metrics.started(wait);
+        *since = now;
+        tracing::debug!(parent: &self.span, attempt, ?wait, "job running");
+        self.running.store(true, Ordering::SeqCst);
+        let guard = Attempt(self.clone());
+        Some((
+            x,
+            Box::new(move |x| {
+                let _guard = guard;
+                f(x)
+            }),
+        ))
+    }
+}
+
+/// Owned by the function of a running attempt, ending the attempt when dropped
+struct Attempt<X, Y>(Arc<HandleInner<X, Y>>);
+
+impl<X, Y> Drop for Attempt<X, Y> {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::SeqCst);
+        self.0.settle();
+    }
+}
+
+impl<X: Send, Y: Send> RunningJob for HandleInner<X, Y> {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn abandon(&self) {
+        self.cancel();
+        if *self.status.lock().unwrap() == Status::Running {
+            self.complete(Err(JobError::Cancelled));
+        }
+    }
+}
+
+impl<X: Debug, Y: Debug> Debug for HandleInner<X, Y> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandleInner")
+            .field("id", &self.id)
+            .field("x", &self.x)
+            .field("status", &self.status)
+            .field("result", &self.result)
+            .field("cancelled", &self.cancelled)
+            .field("attempts", &self.attempts)
+            .finish_non_exhaustive()
+    }
 }
 
 /// A handle that is returned after the system takes a job
 #[derive(Debug)]
-pub struct JobHandle<X, Y> {
+pub struct JobHandle<X, Y>
+where
+    X: Send + Sync,
+    Y: Send + Sync,
+{
     pub(crate) handle_inner: Arc<HandleInner<X, Y>>,
+    queue: Weak<WorkerQueue<X, Y>>,
 }
 
-impl<X, Y> JobHandle<X, Y> {
-    pub(crate) fn new(x: X, f: fn(X) -> Y) -> Self {
+impl<X: Send + Sync, Y: Send + Sync> JobHandle<X, Y> {
+    pub(crate) fn new<F>(
+        x: X,
+        f: F,
+        queue: Weak<WorkerQueue<X, Y>>,
+        metrics: Arc<Metrics>,
+        system_id: u64,
+    ) -> Self
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        metrics.enqueued();
+        let id = NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed);
+        // A child of the span the job is sent from, so work fanned out to several workers still shows up under it
+        let span = tracing::info_span!("job", id, system = system_id, r#type = field::Empty);
+        tracing::debug!(parent: &span, "job queued");
         let handle_inner = HandleInner {
+            id,
+            span,
             x: Mutex::new(Some(x)),
-            f,
+            f: Mutex::new(Some(Box::new(f))),
             result: Mutex::new(None),
             available: Condvar::new(),
+            finished: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            on_settled: Mutex::new(Vec::new()),
+            parked: AtomicBool::new(false),
             status: Mutex::new(Status::Queued),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            progress: Arc::new(Mutex::new(ProgressSink::default())),
+            on_complete: Mutex::new(Vec::new()),
+            waker: Mutex::new(None),
+            attempts: AtomicU32::new(0),
+            retry: Mutex::new(None),
+            deadline: OnceLock::new(),
+            job_type: OnceLock::new(),
+            metrics,
+            since: Mutex::new(Instant::now()),
         };
         Self {
             handle_inner: Arc::new(handle_inner),
+            queue,
         }
     }
     /// Consumes the JobHandle and blocks the current thread until the result is available
-    pub fn get(self) -> Y {
+    pub fn get(self) -> Result<Y, JobError> {
         let mut data_guard = self.handle_inner.result.lock().unwrap();
         // Similar to the message_queue, loop until the data is Some, because the condition variable may spuriously wake up
         loop {
@@ -47,7 +463,293 @@ impl<X, Y> JobHandle<X, Y> {
         }
     }
 
+    /// Returns the result if it is already available, otherwise hands the JobHandle back without blocking
+    pub fn try_get(self) -> Result<Result<Y, JobError>, Self> {
+        let data = self.handle_inner.result.lock().unwrap().take();
+        data.ok_or(self)
+    }
+
+    /// Blocks until the result is available or `timeout` has elapsed, in which case the JobHandle is handed back
+    pub fn get_timeout(self, timeout: Duration) -> Result<Result<Y, JobError>, Self> {
+        let deadline = Instant::now() + timeout;
+        let mut data_guard = self.handle_inner.result.lock().unwrap();
+        loop {
+            if let Some(data) = data_guard.take() {
+                return Ok(data);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                drop(data_guard);
+                return Err(self);
+            }
+            data_guard = self
+                .handle_inner
+                .available
+                .wait_timeout(data_guard, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Identifies the job in tracing output
+    pub fn id(&self) -> u64 {
+        self.handle_inner.id
+    }
+
     pub fn get_status(&self) -> Status {
         self.handle_inner.status.lock().unwrap().clone()
     }
+
+    /// Number of times the job has been started so far, which exceeds 1 once it has been retried
+    pub fn attempts(&self) -> u32 {
+        self.handle_inner.attempts.load(Ordering::Relaxed)
+    }
+
+    /// Returns the last percentage the job reported, along with the values it emitted since the previous call.
+    /// Jobs report through `job_context::report_progress` and `job_context::emit`
+    pub fn take_progress(&self) -> Progress {
+        self.handle_inner.progress.lock().unwrap().take()
+    }
+
+    /// Cancels the job. A queued or pending job is taken out of the queue and resolves immediately, while a running job
+    /// has its cancellation token set, which it may check through `job_context::is_cancelled`.
+    /// Returns false if the job had already finished
+    pub fn cancel(&self) -> bool {
+        if self.handle_inner.resolve_unstarted(JobError::Cancelled) {
+            if let Some(queue) = self.queue.upgrade() {
+                queue.remove_where(&|inner| Arc::ptr_eq(inner, &self.handle_inner));
+            }
+            return true;
+        }
+        let status = self.handle_inner.status.lock().unwrap();
+        if *status == Status::Running {
+            self.handle_inner.cancelled.store(true, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Blocks until every job in `handles` has finished, returning their results in the same order
+pub fn join_all<X, Y, I>(handles: I) -> Vec<Result<Y, JobError>>
+where
+    X: Send + Sync,
+    Y: Send + Sync,
+    I: IntoIterator<Item = JobHandle<X, Y>>,
+{
+    // Waiting on each handle in turn costs no more than the slowest job, since the others finish in the meantime
+    handles.into_iter().map(JobHandle::get).collect()
+}
+
+/// Blocks until any of `handles` has finished, returning its index. Returns None if `handles` is empty
+pub fn wait_any<X: Send + Sync, Y: Send + Sync>(handles: &[JobHandle<X, Y>]) -> Option<usize> {
+    let (sender, receiver) = mpsc::channel();
+    for (i, handle) in handles.iter().enumerate() {
+        let sender = sender.clone();
+        // The receiver is gone once the first job finished, so later sends are simply dropped
+        handle.handle_inner.on_complete(Box::new(move |_| {
+            let _ = sender.send(i);
+        }));
+    }
+    drop(sender);
+    receiver.recv().ok()
+}
+
+/// Hands back the results of the jobs pushed into it in the order they finish, rather than the order they were sent
+#[derive(Debug)]
+pub struct CompletionQueue<X, Y>
+where
+    X: Send + Sync,
+    Y: Send + Sync,
+{
+    handles: HashMap<usize, JobHandle<X, Y>>,
+    next_key: usize,
+    sender: Sender<usize>,
+    receiver: Receiver<usize>,
+}
+
+impl<X: Send + Sync, Y: Send + Sync> CompletionQueue<X, Y> {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            handles: HashMap::new(),
+            next_key: 0,
+            sender,
+            receiver,
+        }
+    }
+
+    /// Adds a job to the queue, returning the key its result will be handed back under
+    pub fn push(&mut self, handle: JobHandle<X, Y>) -> usize {
+        let key = self.next_key;
+        self.next_key += 1;
+        let sender = self.sender.clone();
+        handle.handle_inner.on_complete(Box::new(move |_| {
+            let _ = sender.send(key);
+        }));
+        self.handles.insert(key, handle);
+        key
+    }
+
+    /// Number of jobs whose results have not been handed back yet
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// Returns the next finished job without blocking, if there is one
+    pub fn try_next(&mut self) -> Option<(usize, Result<Y, JobError>)> {
+        let key = self.receiver.try_recv().ok()?;
+        self.take(key)
+    }
+
+    fn take(&mut self, key: usize) -> Option<(usize, Result<Y, JobError>)> {
+        self.handles.remove(&key).map(|handle| (key, handle.get()))
+    }
+}
+
+impl<X: Send + Sync, Y: Send + Sync> Default for CompletionQueue<X, Y> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Blocks until the next job finishes. Ends once every pushed job has been handed back
+impl<X: Send + Sync, Y: Send + Sync> Iterator for CompletionQueue<X, Y> {
+    type Item = (usize, Result<Y, JobError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.handles.is_empty() {
+            return None;
+        }
+        let key = self.receiver.recv().ok()?;
+        self.take(key)
+    }
+}
+
+/// Lets async code await a job without blocking an executor thread. Works with any executor, as the worker that
+/// finishes the job wakes the awaiting task itself
+impl<X: Send + Sync, Y: Send + Sync> IntoFuture for JobHandle<X, Y> {
+    type Output = Result<Y, JobError>;
+    type IntoFuture = JobFuture<X, Y>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        JobFuture { handle: self }
+    }
+}
+
+/// The future a JobHandle turns into when it is awaited. It owns the handle, so once it has taken the result, nothing
+/// is left that could wait on the job again
+#[derive(Debug)]
+pub struct JobFuture<X, Y>
+where
+    X: Send + Sync,
+    Y: Send + Sync,
+{
+    handle: JobHandle<X, Y>,
+}
+
+impl<X: Send + Sync, Y: Send + Sync> Future for JobFuture<X, Y> {
+    type Output = Result<Y, JobError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let handle_inner = &self.handle.handle_inner;
+        let mut data_guard = handle_inner.result.lock().unwrap();
+        if let Some(data) = data_guard.take() {
+            return Poll::Ready(data);
+        }
+        // Registered while holding the result lock, so the job cannot complete between the check and the registration
+        *handle_inner.waker.lock().unwrap() = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Hands out a JobHandle for every run of a job sent through `JobSystem::send_job_every`
+#[derive(Debug)]
+pub struct RecurringJob<X, Y>
+where
+    X: Send + Sync,
+    Y: Send + Sync,
+{
+    pub(crate) runs: Receiver<JobHandle<X, Y>>,
+    pub(crate) stopped: Arc<AtomicBool>,
+}
+
+impl<X: Send + Sync, Y: Send + Sync> RecurringJob<X, Y> {
+    /// Schedules no further runs. Runs that were already sent are not affected
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns the handle of the next run if it has already been sent, without blocking
+    pub fn try_next(&self) -> Option<JobHandle<X, Y>> {
+        self.runs.try_recv().ok()
+    }
+}
+
+/// Blocks until the next run is sent. Ends once the job is stopped or its JobSystem is dropped
+impl<X: Send + Sync, Y: Send + Sync> Iterator for RecurringJob<X, Y> {
+    type Item = JobHandle<X, Y>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runs.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::system::{job_context, job_system::JobSystem, test_util::block_on};
+
+    #[test]
+    fn awaiting_a_handle_waits_for_the_job() {
+        let mut system = JobSystem::new();
+        system.add_worker();
+        let handle = system.send_job(2, |x: u32| {
+            thread::sleep(Duration::from_millis(20));
+            x * 2
+        });
+        assert_eq!(block_on(handle), Ok(4));
+    }
+
+    #[test]
+    fn cancelled_jobs_resolve_without_running_or_stop_early() {
+        let mut system = JobSystem::new();
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        let queued = system.send_job(1, move |x: u32| {
+            flag.store(true, Ordering::Relaxed);
+            x
+        });
+        assert!(queued.cancel());
+        assert_eq!(queued.get_status(), Status::Cancelled);
+
+        system.add_worker();
+        let (started, running) = mpsc::channel();
+        let looping = system.send_job(2, move |x| {
+            started.send(()).unwrap();
+            while !job_context::is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            x
+        });
+        running.recv().unwrap();
+        assert!(looping.cancel());
+        assert_eq!(looping.get(), Err(JobError::Cancelled));
+        assert_eq!(queued.get(), Err(JobError::Cancelled));
+        assert!(!ran.load(Ordering::Relaxed));
+
+        let finished = system.send_job(3, |x| x);
+        while finished.get_status() != Status::Completed {
+            thread::yield_now();
+        }
+        assert!(!finished.cancel());
+        assert_eq!(finished.get(), Ok(3));
+    }
 }
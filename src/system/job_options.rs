@@ -0,0 +1,210 @@
+use std::{
+    fmt::{self, Debug},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::{job_handle::JobError, scheduler::DEFAULT_QUEUE};
+
+/// Priority of a job. Higher priorities are served first, and jobs of equal priority are served in submission order
+pub type Priority = i32;
+
+/// Per-job settings that are passed along with `JobSystem::send_job_with`
+#[derive(Clone, Debug, Default)]
+pub struct JobOptions {
+    pub(crate) priority: Priority,
+    deadline: Option<Deadline>,
+    pub(crate) job_type: Option<String>,
+    queue: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Deadline {
+    At(Instant),
+    /// Counted from the moment the job is sent, so the same options can be reused for several jobs
+    After(Duration),
+}
+
+impl JobOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the priority of the job, which defaults to 0
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Names the kind of work the job does, which its run time and outcome are counted under in `JobSystem::stats`
+    pub fn job_type(mut self, job_type: impl Into<String>) -> Self {
+        self.job_type = Some(job_type.into());
+        self
+    }
+
+    /// Sends the job to the named queue, which only the workers subscribed to it serve. Defaults to `DEFAULT_QUEUE`.
+    /// A job sent to a queue no worker serves waits until one is added, see `JobSystem::serves_queue`
+    pub fn queue(mut self, queue: impl Into<String>) -> Self {
+        self.queue = Some(queue.into());
+        self
+    }
+
+    pub(crate) fn queue_name(&self) -> &str {
+        self.queue.as_deref().unwrap_or(DEFAULT_QUEUE)
+    }
+
+    /// Resolves the job to `JobError::TimedOut` if it has not finished by `deadline`, whether it is still queued or
+    /// already running
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(Deadline::At(deadline));
+        self
+    }
+
+    /// Same as `deadline`, with the deadline set `timeout` after the job is sent
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(Deadline::After(timeout));
+        self
+    }
+
+    /// The deadline of a job sent at `sent`
+    pub(crate) fn deadline_from(&self, sent: Instant) -> Option<Instant> {
+        self.deadline.map(|deadline| match deadline {
+            Deadline::At(at) => at,
+            Deadline::After(timeout) => sent + timeout,
+        })
+    }
+}
+
+type RetryPredicate<Y> = Arc<dyn Fn(&Result<Y, JobError>) -> bool + Send + Sync>;
+
+/// How often and how soon a job is run again when an attempt fails, as passed to `JobSystem::send_job_with_retry`
+pub struct RetryPolicy<Y> {
+    pub(crate) max_attempts: u32,
+    initial_backoff: Duration,
+    multiplier: f64,
+    max_backoff: Duration,
+    retry_if: RetryPredicate<Y>,
+}
+
+impl<Y> RetryPolicy<Y> {
+    /// Runs the job at most `max_attempts` times in total. By default an attempt is retried if it panicked, after a
+    /// backoff starting at 100ms and doubling with every attempt, up to 30s
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_backoff: Duration::from_secs(30),
+            retry_if: Arc::new(|result| result.is_err()),
+        }
+    }
+
+    /// Sets the delay before the first retry
+    pub fn backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Sets the factor the delay grows by with every further retry
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Caps the delay between two attempts
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Decides from the outcome of an attempt whether it should be retried, for example by checking a returned status
+    pub fn retry_if<P>(mut self, predicate: P) -> Self
+    where
+        P: Fn(&Result<Y, JobError>) -> bool + Send + Sync + 'static,
+    {
+        self.retry_if = Arc::new(predicate);
+        self
+    }
+
+    pub(crate) fn should_retry(&self, result: &Result<Y, JobError>) -> bool {
+        (self.retry_if)(result)
+    }
+
+    /// The delay after the given attempt, counting from 1
+    pub(crate) fn backoff_after(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        Duration::try_from_secs_f64(self.initial_backoff.as_secs_f64() * factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+impl<Y> Clone for RetryPolicy<Y> {
+    fn clone(&self) -> Self {
+        Self {
+            max_attempts: self.max_attempts,
+            initial_backoff: self.initial_backoff,
+            multiplier: self.multiplier,
+            max_backoff: self.max_backoff,
+            retry_if: self.retry_if.clone(),
+        }
+    }
+}
+
+impl<Y> Debug for RetryPolicy<Y> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("multiplier", &self.multiplier)
+            .field("max_backoff", &self.max_backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::system::job_system::JobSystemBuilder;
+
+    #[test]
+    fn backoff_grows_by_the_multiplier_up_to_its_cap() {
+        let policy = RetryPolicy::<()>::new(5)
+            .backoff(Duration::from_millis(10))
+            .multiplier(3.0)
+            .max_backoff(Duration::from_millis(100));
+        let backoffs: Vec<_> = (1..=4)
+            .map(|attempt| policy.backoff_after(attempt))
+            .collect();
+        assert_eq!(backoffs, [10, 30, 90, 100].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn attempts_are_retried_after_their_backoff_until_the_policy_gives_up() {
+        let mut system = JobSystemBuilder::new().workers(1).build();
+        let attempt = |counter: &Arc<AtomicU32>| {
+            let counter = counter.clone();
+            move |_: ()| counter.fetch_add(1, Ordering::Relaxed) + 1
+        };
+        let policy = |max_attempts| {
+            RetryPolicy::new(max_attempts)
+                .backoff(Duration::from_millis(20))
+                .retry_if(|result| matches!(result, Ok(n) if *n < 3))
+        };
+
+        let started = Instant::now();
+        let counter = Arc::new(AtomicU32::new(0));
+        let handle =
+            system.send_job_with_retry((), attempt(&counter), JobOptions::new(), policy(5));
+        assert_eq!(handle.get(), Ok(3));
+        // 20ms after the first attempt and 40ms after the second
+        assert!(started.elapsed() >= Duration::from_millis(60));
+
+        let counter = Arc::new(AtomicU32::new(0));
+        let handle =
+            system.send_job_with_retry((), attempt(&counter), JobOptions::new(), policy(2));
+        assert_eq!(handle.get(), Ok(2));
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+    }
+}
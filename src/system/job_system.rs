@@ -1,71 +1,1007 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Debug, Display},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
 
 use super::{
-    job_handle::JobHandle,
+    job_handle::{HandleInner, JobError, JobHandle, RecurringJob, Status},
+    job_options::{JobOptions, RetryPolicy},
     message_queue::MessageQueue,
-    worker::{Worker, WorkerMessage},
+    metrics::{Metrics, SystemStats},
+    result_cache::ResultCache,
+    scheduler::{Scheduler, DEFAULT_QUEUE},
+    timer::{Fired, Timer},
+    type_limits::TypeLimits,
+    work_stealing::WorkStealingQueue,
+    worker::{Worker, WorkerQueue},
 };
 
+/// Source of the ids that identify systems in tracing output
+static NEXT_SYSTEM_ID: AtomicU64 = AtomicU64::new(0);
+
+/// How often `JobSystem::shutdown` checks whether its workers have exited
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
 #[derive(Debug)]
-pub(crate) struct JobSystem<X, Y>
+pub struct JobSystem<X, Y>
 where
     X: Send + Sync,
     Y: Send + Sync,
 {
     workers: Vec<Worker>,
-    message_queue: Arc<MessageQueue<WorkerMessage<X, Y>>>,
+    /// Workers that were asked to join but have not exited yet, since they only leave once they run out of work
+    retiring: usize,
+    message_queue: Arc<WorkerQueue<X, Y>>,
+    /// Requeues jobs once their retry backoff has passed
+    timer: Arc<Timer>,
+    metrics: Arc<Metrics>,
+    /// Identifies the system in tracing output
+    id: u64,
+    /// Most jobs the queue holds before submissions have to wait, if bounded
+    capacity: Option<usize>,
+    /// Set once the system aborts, after which jobs waiting on dependencies are cancelled instead of queued
+    aborted: Arc<AtomicBool>,
+    /// Only consulted by systems of `Value` jobs, through `send_cached_job`
+    result_cache: Option<Arc<ResultCache>>,
+    /// Every job is queued through here, scheduled and recurring ones included once they come due. Retries skip it, as
+    /// they keep the slot of their first attempt
+    type_limits: Arc<TypeLimits<X, Y>>,
+}
+
+/// How `JobSystem::shutdown` treats the jobs that have not finished yet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Runs every queued job before the workers exit, which is also what dropping the system does
+    #[default]
+    Drain,
+    /// Resolves every job that has not started as cancelled, and sets the cancellation token of running jobs
+    Abort,
+}
+
+/// Returned when a job could not be submitted because the queue stayed full, handing its input back
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueFull<X>(pub X);
+
+impl<X> Display for QueueFull<X> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "queue is full")
+    }
+}
+
+impl<X: Debug> Error for QueueFull<X> {}
+
+/// Returned when a job is sent to a named queue that no worker serves, where it would never run, handing its input back
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnservedQueue<X>(pub X);
+
+impl<X> Display for UnservedQueue<X> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no worker serves the queue")
+    }
+}
+
+impl<X: Debug> Error for UnservedQueue<X> {}
+
+/// Returned when `try_map_with` does not queue a batch, handing its inputs back
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchRejected<X> {
+    /// The batch does not fit in the queue right now, but may once workers have taken some jobs
+    QueueFull(X),
+    /// The batch holds more jobs than the queue ever does, so it never fits
+    ExceedsCapacity(X),
+}
+
+impl<X> Display for BatchRejected<X> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::QueueFull(_) => write!(f, "queue is full"),
+            Self::ExceedsCapacity(_) => write!(f, "batch exceeds capacity"),
+        }
+    }
+}
+
+impl<X: Debug> Error for BatchRejected<X> {}
+
+/// Configures a `JobSystem` before it is created
+#[derive(Clone, Debug, Default)]
+pub struct JobSystemBuilder {
+    scheduler: Scheduler,
+    workers: usize,
+    /// Workers serving named queues, along with the queues they serve
+    queue_workers: Vec<(Vec<String>, usize)>,
+    capacity: Option<usize>,
+    result_cache: Option<Arc<ResultCache>>,
+    type_limits: HashMap<String, usize>,
 }
 
-impl<X: Send + Sync, Y: Send + Sync> JobSystem<X, Y> {
-    pub(crate) fn new() -> Self {
-        Self {
-            message_queue: MessageQueue::new(),
+impl JobSystemBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Selects how jobs are handed out to workers, defaulting to `Scheduler::SharedQueue`
+    pub fn scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Number of workers serving the default queue the system starts with. More can be added later through
+    /// `JobSystem::add_worker`
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Starts the system with `workers` more workers, each serving the named queues. Jobs sent to a queue only run on
+    /// the workers serving it, so slow work in one queue cannot hold up another
+    pub fn workers_for(mut self, queues: &[&str], workers: usize) -> Self {
+        let queues = queues.iter().map(|&queue| queue.into()).collect();
+        self.queue_workers.push((queues, workers));
+        self
+    }
+
+    /// Bounds the queue to `capacity` jobs, which is at least 1. Once it is full, `send_job` and the other blocking
+    /// submission methods wait for a worker to take a job, while `try_send_job` and `send_job_timeout` give up.
+    /// Jobs that wait on dependencies or a scheduled time only count once they are queued
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity.max(1));
+        self
+    }
+
+    /// Lets systems of `Value` jobs resolve jobs from `cache` instead of running them, see `JobSystem::send_cached_job`
+    pub fn result_cache(mut self, cache: ResultCache) -> Self {
+        self.result_cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Runs at most `limit` jobs of `job_type` at once, which is at least 1. Further jobs of the type stay pending until
+    /// the function of one of those returns, even if it outlives its deadline, and still count against the capacity
+    /// meanwhile. Types without a limit are unlimited
+    pub fn type_limit(mut self, job_type: &str, limit: usize) -> Self {
+        self.type_limits.insert(job_type.into(), limit.max(1));
+        self
+    }
+
+    pub fn build<X: Send + Sync + 'static, Y: Send + Sync + 'static>(self) -> JobSystem<X, Y> {
+        let message_queue: Arc<WorkerQueue<X, Y>> = match self.scheduler {
+            Scheduler::SharedQueue => Arc::new(MessageQueue::new()),
+            Scheduler::WorkStealing => Arc::new(WorkStealingQueue::new()),
+        };
+        let type_limits = Arc::new(TypeLimits::new(
+            self.type_limits,
+            Arc::downgrade(&message_queue),
+        ));
+        let mut system = JobSystem {
+            message_queue,
+            type_limits,
             workers: Vec::new(),
+            retiring: 0,
+            timer: Arc::new(Timer::new()),
+            metrics: Arc::new(Metrics::new()),
+            id: NEXT_SYSTEM_ID.fetch_add(1, Ordering::Relaxed),
+            capacity: self.capacity,
+            aborted: Arc::new(AtomicBool::new(false)),
+            result_cache: self.result_cache,
+        };
+        (0..self.workers).for_each(|_| system.add_worker());
+        for (queues, workers) in &self.queue_workers {
+            let queues: Vec<&str> = queues.iter().map(String::as_str).collect();
+            (0..*workers).for_each(|_| system.add_worker_for(&queues));
+        }
+        system
+    }
+}
+
+impl<X: Send + Sync + 'static, Y: Send + Sync + 'static> JobSystem<X, Y> {
+    pub fn new() -> Self {
+        JobSystemBuilder::new().build()
+    }
+
+    /// Queues `f` to be run on `x` by the next available worker. `f` may be any closure, so jobs can carry their own state
+    pub fn send_job<F>(&mut self, x: X, f: F) -> JobHandle<X, Y>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        self.send_job_with(x, f, JobOptions::default())
+    }
+
+    /// Same as `send_job`, with the job sent to the named queue. Returns the input if no worker serves the queue, see
+    /// `serves_queue`
+    pub fn send_job_to<F>(
+        &mut self,
+        queue: &str,
+        x: X,
+        f: F,
+    ) -> Result<JobHandle<X, Y>, UnservedQueue<X>>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        if !self.serves_queue(queue) {
+            return Err(UnservedQueue(x));
+        }
+        Ok(self.send_job_with(x, f, JobOptions::new().queue(queue)))
+    }
+
+    /// Same as `send_job`, with the job configured by `options`
+    pub fn send_job_with<F>(&mut self, x: X, f: F, options: JobOptions) -> JobHandle<X, Y>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        self.wait_for_room(1, None);
+        let handle = self.new_handle(x, f);
+        self.apply_options(&handle.handle_inner, &options);
+        self.type_limits.send(
+            handle.handle_inner.clone(),
+            options.queue_name(),
+            options.priority,
+        );
+        handle
+    }
+
+    /// Same as `send_job`, but returns the input right away instead of waiting if the queue is full
+    pub fn try_send_job<F>(&mut self, x: X, f: F) -> Result<JobHandle<X, Y>, QueueFull<X>>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        self.try_send_job_with(x, f, JobOptions::default())
+    }
+
+    /// Same as `try_send_job`, with the job configured by `options`
+    pub fn try_send_job_with<F>(
+        &mut self,
+        x: X,
+        f: F,
+        options: JobOptions,
+    ) -> Result<JobHandle<X, Y>, QueueFull<X>>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        self.send_job_timeout_with(x, f, options, Duration::ZERO)
+    }
+
+    /// Same as `send_job`, but waits at most `timeout` for room in the queue before returning the input
+    pub fn send_job_timeout<F>(
+        &mut self,
+        x: X,
+        f: F,
+        timeout: Duration,
+    ) -> Result<JobHandle<X, Y>, QueueFull<X>>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        self.send_job_timeout_with(x, f, JobOptions::default(), timeout)
+    }
+
+    /// Same as `send_job_timeout`, with the job configured by `options`
+    pub fn send_job_timeout_with<F>(
+        &mut self,
+        x: X,
+        f: F,
+        options: JobOptions,
+        timeout: Duration,
+    ) -> Result<JobHandle<X, Y>, QueueFull<X>>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        if self
+            .wait_for_room(1, Some(Instant::now() + timeout))
+            .is_none()
+        {
+            return Err(QueueFull(x));
+        }
+        Ok(self.send_job_with(x, f, options))
+    }
+
+    /// Same as `send_job_with`, but failed attempts are run again as described by `policy`. The input is cloned for
+    /// every attempt, and the handle only resolves once an attempt succeeds or the policy gives up
+    pub fn send_job_with_retry<F>(
+        &mut self,
+        x: X,
+        f: F,
+        options: JobOptions,
+        policy: RetryPolicy<Y>,
+    ) -> JobHandle<X, Y>
+    where
+        X: Clone,
+        F: Fn(X) -> Y + Send + Sync + 'static,
+    {
+        // Only the first attempt waits for room, as later ones are requeued from the timer
+        self.wait_for_room(1, None);
+        let f = Arc::new(f);
+        let attempt = f.clone();
+        let handle = self.new_handle(x.clone(), move |x| attempt(x));
+        let (queue, timer) = (
+            Arc::downgrade(&self.message_queue),
+            Arc::downgrade(&self.timer),
+        );
+        let (queue_name, priority) = (options.queue_name().to_string(), options.priority);
+        let mut attempts = 1;
+        *handle.handle_inner.retry.lock().unwrap() = Some(Box::new(move |inner, result| {
+            if attempts >= policy.max_attempts || !policy.should_retry(result) {
+                return false;
+            }
+            let (Some(queue), Some(timer)) = (queue.upgrade(), timer.upgrade()) else {
+                return false;
+            };
+            let attempt = f.clone();
+            if !inner.rearm(x.clone(), Box::new(move |x| attempt(x))) {
+                return false;
+            }
+            let backoff = policy.backoff_after(attempts);
+            attempts += 1;
+            let (inner, queue_name) = (inner.clone(), queue_name.clone());
+            timer.schedule(
+                Instant::now() + backoff,
+                // A flushed backoff is cut short, so the attempt still runs before the workers leave
+                Box::new(move |_| queue.send(inner, &queue_name, priority)),
+            );
+            true
+        }));
+        self.apply_options(&handle.handle_inner, &options);
+        self.type_limits.send(
+            handle.handle_inner.clone(),
+            options.queue_name(),
+            options.priority,
+        );
+        handle
+    }
+
+    /// Queues `f` once for every element of `inputs`, returning the handles in the same order.
+    /// The whole batch is queued under a single lock
+    pub fn map<I, F>(&mut self, inputs: I, f: F) -> Vec<JobHandle<X, Y>>
+    where
+        I: IntoIterator<Item = X>,
+        F: Fn(X) -> Y + Send + Sync + 'static,
+    {
+        self.map_with(inputs, f, JobOptions::default())
+    }
+
+    /// Same as `map`, with every job configured by `options`
+    pub fn map_with<I, F>(&mut self, inputs: I, f: F, options: JobOptions) -> Vec<JobHandle<X, Y>>
+    where
+        I: IntoIterator<Item = X>,
+        F: Fn(X) -> Y + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let mut inputs = inputs.into_iter().peekable();
+        let mut handles = Vec::new();
+        // A bounded queue takes the batch in chunks, each as large as the room there is at the time
+        while inputs.peek().is_some() {
+            let room = self.wait_for_room(1, None).unwrap_or_default();
+            let chunk: Vec<_> = inputs
+                .by_ref()
+                .take(room)
+                .map(|x| {
+                    let f = f.clone();
+                    let handle = self.new_handle(x, move |x| f(x));
+                    self.apply_options(&handle.handle_inner, &options);
+                    handle
+                })
+                .collect();
+            self.type_limits.send_batch(
+                chunk.iter().map(|h| h.handle_inner.clone()).collect(),
+                options.queue_name(),
+                options.priority,
+            );
+            handles.extend(chunk);
+        }
+        handles
+    }
+
+    /// Same as `map_with`, but queues either the whole batch or, if it does not fit in the queue right now, none of it
+    pub fn try_map_with<F>(
+        &mut self,
+        inputs: Vec<X>,
+        f: F,
+        options: JobOptions,
+    ) -> Result<Vec<JobHandle<X, Y>>, BatchRejected<Vec<X>>>
+    where
+        F: Fn(X) -> Y + Send + Sync + 'static,
+    {
+        if self
+            .capacity
+            .is_some_and(|capacity| inputs.len() > capacity)
+        {
+            return Err(BatchRejected::ExceedsCapacity(inputs));
+        }
+        if self
+            .wait_for_room(inputs.len(), Some(Instant::now()))
+            .is_none()
+        {
+            return Err(BatchRejected::QueueFull(inputs));
+        }
+        Ok(self.map_with(inputs, f, options))
+    }
+
+    /// Queues `f` once every job in `deps` has completed. If any of them fails or is cancelled, the job resolves to
+    /// `JobError::DependencyFailed` without running
+    pub fn send_job_after<F>(&mut self, deps: &[&JobHandle<X, Y>], x: X, f: F) -> JobHandle<X, Y>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        self.send_job_after_with(deps, x, f, JobOptions::default())
+    }
+
+    /// Same as `send_job_after`, with the job configured by `options`. Its deadline starts counting right away,
+    /// including the time spent waiting on `deps`
+    pub fn send_job_after_with<F>(
+        &mut self,
+        deps: &[&JobHandle<X, Y>],
+        x: X,
+        f: F,
+        options: JobOptions,
+    ) -> JobHandle<X, Y>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        let handle = self.new_handle(x, f);
+        self.queue_after(deps, &handle, options, |_, result| result.is_ok());
+        handle
+    }
+
+    /// Queues `f` once every job in `deps` has finished, passing it their results in the order of `deps`.
+    /// The job runs even if some of its dependencies failed, so it can decide how to handle them
+    pub fn send_job_after_with_outputs<F>(
+        &mut self,
+        deps: &[&JobHandle<X, Y>],
+        x: X,
+        f: F,
+        options: JobOptions,
+    ) -> JobHandle<X, Y>
+    where
+        Y: Clone,
+        F: FnOnce(X, Vec<Result<Y, JobError>>) -> Y + Send + 'static,
+    {
+        let outputs = Arc::new(Mutex::new(vec![None; deps.len()]));
+        let collected = outputs.clone();
+        let handle = self.new_handle(x, move |x| {
+            let outputs = collected.lock().unwrap().drain(..).flatten().collect();
+            f(x, outputs)
+        });
+        self.queue_after(deps, &handle, options, move |i, result| {
+            outputs.lock().unwrap()[i] = Some(result.clone());
+            true
+        });
+        handle
+    }
+
+    /// Queues `f` once `at` has come. Until then the job is pending, and can be cancelled like any other.
+    /// Should the system be dropped first, the job resolves to `JobError::Cancelled` without running
+    pub fn send_job_at<F>(&mut self, at: Instant, x: X, f: F) -> JobHandle<X, Y>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        self.send_job_at_with(at, x, f, JobOptions::default())
+    }
+
+    /// Same as `send_job_at`, with the job configured by `options`. Its deadline starts counting right away, including
+    /// the time spent waiting for `at`
+    pub fn send_job_at_with<F>(
+        &mut self,
+        at: Instant,
+        x: X,
+        f: F,
+        options: JobOptions,
+    ) -> JobHandle<X, Y>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        let handle = self.new_handle(x, f);
+        let inner = handle.handle_inner.clone();
+        inner.hold();
+        self.apply_options(&inner, &options);
+        let type_limits = self.type_limits.clone();
+        let (queue_name, priority) = (options.queue_name().to_string(), options.priority);
+        self.timer.schedule(
+            at,
+            Box::new(move |fired| {
+                if fired == Fired::Flushed {
+                    inner.resolve_unstarted(JobError::Cancelled);
+                    return;
+                }
+                if inner.release() {
+                    type_limits.send(inner, &queue_name, priority);
+                }
+            }),
+        );
+        handle
+    }
+
+    /// Same as `send_job_at`, with the job queued once `delay` has passed
+    pub fn send_job_after_delay<F>(&mut self, delay: Duration, x: X, f: F) -> JobHandle<X, Y>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        self.send_job_at(Instant::now() + delay, x, f)
+    }
+
+    /// Queues `f` on a clone of `x` every `interval`, with the first run one `interval` from now. A run that comes due
+    /// while the previous one is still pending, queued or running is skipped, so slow jobs do not pile up in the queue.
+    /// Dropping the returned RecurringJob stops the job as well. Panics if `interval` is zero, as every run would come
+    /// due at the same instant
+    pub fn send_job_every<F>(&mut self, interval: Duration, x: X, f: F) -> RecurringJob<X, Y>
+    where
+        X: Clone,
+        F: Fn(X) -> Y + Send + Sync + 'static,
+    {
+        self.send_job_every_with(interval, x, f, JobOptions::default())
+    }
+
+    /// Same as `send_job_every`, with every run configured by `options`. The deadline of a run starts counting once it
+    /// comes due
+    pub fn send_job_every_with<F>(
+        &mut self,
+        interval: Duration,
+        x: X,
+        f: F,
+        options: JobOptions,
+    ) -> RecurringJob<X, Y>
+    where
+        X: Clone,
+        F: Fn(X) -> Y + Send + Sync + 'static,
+    {
+        assert!(
+            !interval.is_zero(),
+            "the interval of a recurring job must not be zero"
+        );
+        let (sender, runs) = mpsc::channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let recurrence = Recurrence {
+            x,
+            f: Arc::new(f),
+            interval,
+            options,
+            previous: Mutex::new(Weak::new()),
+            runs: sender,
+            stopped: stopped.clone(),
+            queue: Arc::downgrade(&self.message_queue),
+            type_limits: self.type_limits.clone(),
+            timer: Arc::downgrade(&self.timer),
+            metrics: self.metrics.clone(),
+            system_id: self.id,
+        };
+        Recurrence::schedule(Arc::new(recurrence), &self.timer, Instant::now() + interval);
+        RecurringJob { runs, stopped }
+    }
+
+    /// Waits for room for `needed` more jobs in a bounded queue, returning how many fit, or None if there was not
+    /// enough room by `until`. Unbounded queues always have room
+    fn wait_for_room(&self, needed: usize, until: Option<Instant>) -> Option<usize> {
+        match self.capacity {
+            None => Some(usize::MAX),
+            Some(capacity) => self.metrics.wait_for_room(capacity, needed, until),
+        }
+    }
+
+    fn new_handle<F>(&self, x: X, f: F) -> JobHandle<X, Y>
+    where
+        F: FnOnce(X) -> Y + Send + 'static,
+    {
+        JobHandle::new(
+            x,
+            f,
+            Arc::downgrade(&self.message_queue),
+            self.metrics.clone(),
+            self.id,
+        )
+    }
+
+    fn apply_options(&self, inner: &Arc<HandleInner<X, Y>>, options: &JobOptions) {
+        apply_options(inner, options, &self.timer, &self.message_queue);
+    }
+
+    /// Holds `handle` in the pending state until all of `deps` have finished. `record` sees the result of each
+    /// dependency as it comes in and returns whether the job may still run
+    fn queue_after<R>(
+        &self,
+        deps: &[&JobHandle<X, Y>],
+        handle: &JobHandle<X, Y>,
+        options: JobOptions,
+        record: R,
+    ) where
+        R: Fn(usize, &Result<Y, JobError>) -> bool + Send + Sync + 'static,
+    {
+        let inner = &handle.handle_inner;
+        inner.hold();
+        self.apply_options(inner, &options);
+        let (queue_name, priority) = (options.queue_name().to_string(), options.priority);
+        if deps.is_empty() && inner.release() {
+            self.type_limits.send(inner.clone(), &queue_name, priority);
+            return;
+        }
+        let queue_name = Arc::new(queue_name);
+
+        let remaining = Arc::new(AtomicUsize::new(deps.len()));
+        let failed = Arc::new(AtomicBool::new(false));
+        let record = Arc::new(record);
+        for (i, dep) in deps.iter().enumerate() {
+            let (remaining, failed, record) = (remaining.clone(), failed.clone(), record.clone());
+            let (inner, type_limits) = (inner.clone(), self.type_limits.clone());
+            let (aborted, queue_name) = (self.aborted.clone(), queue_name.clone());
+            dep.handle_inner.on_complete(Box::new(move |result| {
+                if !record(i, result) {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                if remaining.fetch_sub(1, Ordering::AcqRel) != 1 {
+                    return;
+                }
+                if failed.load(Ordering::Relaxed) {
+                    inner.resolve_unstarted(JobError::DependencyFailed);
+                } else if aborted.load(Ordering::Relaxed) {
+                    inner.resolve_unstarted(JobError::Cancelled);
+                } else if inner.release() {
+                    type_limits.send(inner, &queue_name, priority);
+                }
+            }));
+        }
+    }
+
+    /// Whether jobs sent to `queue` are run. The default queue always is, as jobs wait there for the first worker to be
+    /// added, while a named queue needs a worker serving it that is not retiring
+    pub fn serves_queue(&self, queue: &str) -> bool {
+        queue == DEFAULT_QUEUE || self.message_queue.serves(queue)
+    }
+
+    /// Adds a worker serving the default queue
+    pub fn add_worker(&mut self) {
+        self.add_worker_for(&[DEFAULT_QUEUE]);
+    }
+
+    /// Adds a worker serving the named queues, taking the job with the highest priority across them first.
+    /// Serves the default queue if `queues` is empty
+    pub fn add_worker_for(&mut self, queues: &[&str]) {
+        self.reap_workers();
+        let queues: Vec<String> = queues.iter().map(|&queue| queue.into()).collect();
+        self.workers
+            .push(Worker::new(self.message_queue.clone(), &queues));
+        self.metrics.set_workers(self.worker_count());
+    }
+
+    /// Retires the most recently added worker that is not the last one serving a named queue. It exits once the queues
+    /// it serves run empty, so queued jobs are never dropped. Returns false if no worker can be removed
+    pub fn remove_worker(&mut self) -> bool {
+        self.reap_workers();
+        if !self.message_queue.join_one() {
+            return false;
+        }
+        self.retiring += 1;
+        self.metrics.set_workers(self.worker_count());
+        true
+    }
+
+    /// Adds or retires workers until the pool holds `n` of them. Added workers serve the default queue, and retired
+    /// ones are picked as by `remove_worker`, so the pool keeps more than `n` workers if it takes that many to serve
+    /// every named queue
+    pub fn resize(&mut self, n: usize) {
+        while self.worker_count() < n {
+            self.add_worker();
+        }
+        while self.worker_count() > n && self.remove_worker() {}
+    }
+
+    /// Identifies the system in tracing output, where every job span carries it
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns a snapshot of the counters and timings collected since the system was created
+    pub fn stats(&self) -> SystemStats {
+        self.metrics.snapshot(self.worker_count())
+    }
+
+    /// Number of workers serving jobs, not counting retired ones that are still finishing their last job
+    pub fn worker_count(&self) -> usize {
+        self.workers.len() - self.retiring
+    }
+
+    /// Stops the system once its jobs are dealt with according to `mode`. Scheduled jobs that are not due yet are
+    /// cancelled either way, and recurring jobs stop. Should `deadline` pass before every worker exits, the system
+    /// aborts if it was draining, and the jobs still running are resolved as cancelled and left behind on their
+    /// threads. Returns whether every worker exited in time
+    pub fn shutdown(mut self, mode: ShutdownMode, deadline: Option<Duration>) -> bool {
+        let until = deadline.map(|deadline| Instant::now() + deadline);
+        // Jobs waiting out a retry backoff are requeued, and aborting takes them back out right after
+        self.timer.flush();
+        if mode == ShutdownMode::Abort {
+            self.abort();
+        }
+        self.message_queue.join_all();
+        self.retiring = self.workers.len();
+        if self.join_workers(until) {
+            return true;
+        }
+        self.abort();
+        for worker in &mut self.workers {
+            worker.abandon();
+        }
+        false
+    }
+
+    /// Creates a handle that has already finished with `result`, for a job whose outcome was recorded by an earlier run
+    fn restore_job(&self, x: X, result: Result<Y, JobError>) -> JobHandle<X, Y> {
+        let handle = self.new_handle(x, |_| unreachable!("restored jobs are never run"));
+        handle.handle_inner.restore(result);
+        handle
+    }
+
+    /// Cancels every queued job, every job that is still waiting on dependencies or a slot for its type, and every
+    /// running job
+    fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+        // Emptied first, as cancelling the jobs in the queue frees their slots for the jobs waiting on them
+        for inner in self.type_limits.drain() {
+            inner.resolve_unstarted(JobError::Cancelled);
+        }
+        while let Some(inner) = self.message_queue.remove_where(&|_| true) {
+            inner.resolve_unstarted(JobError::Cancelled);
+        }
+        self.workers.iter().for_each(Worker::cancel_current);
+    }
+
+    /// Waits until every worker has exited, or until `until` has passed. Returns whether they all exited
+    fn join_workers(&mut self, until: Option<Instant>) -> bool {
+        loop {
+            self.reap_workers();
+            if self.workers.is_empty() {
+                return true;
+            }
+            if until.is_some_and(|until| Instant::now() >= until) {
+                return false;
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
         }
     }
 
-    pub(crate) fn send_job(&mut self, x: X, f: fn(X) -> Y) -> JobHandle<X, Y> {
-        let handle = JobHandle::new(x, f);
-        self.message_queue
-            .send(WorkerMessage::Handle(handle.handle_inner.clone()));
+    /// Joins the threads of retired workers that have exited
+    fn reap_workers(&mut self) {
+        let before = self.workers.len();
+        self.workers.retain(|w| !w.is_finished());
+        self.retiring -= before - self.workers.len();
+    }
+}
+
+impl JobSystem<Value, Value> {
+    /// Same as `send_job_with`, except that if the system has a result cache and a job of the same type already
+    /// completed on an equal input, the handle resolves right away with the cached output, without taking a worker.
+    /// Otherwise the output is cached once the job completes. Jobs without a type are never cached
+    pub fn send_cached_job<F>(
+        &mut self,
+        x: Value,
+        f: F,
+        options: JobOptions,
+    ) -> JobHandle<Value, Value>
+    where
+        F: FnOnce(Value) -> Value + Send + 'static,
+    {
+        if let Some(handle) = self.cached_job(&x, &options) {
+            return handle;
+        }
+        let job_type = options.job_type.clone();
+        let handle = self.send_job_with(x.clone(), f, options);
+        self.cache_output(&handle, job_type, x);
         handle
     }
+
+    /// Returns a resolved handle if the output of the job is in the result cache
+    fn cached_job(&self, x: &Value, options: &JobOptions) -> Option<JobHandle<Value, Value>> {
+        let job_type = options.job_type.as_ref()?;
+        let output = self.result_cache.as_ref()?.get(job_type, x)?;
+        let handle = self.new_handle(x.clone(), |_| unreachable!("cached jobs are never run"));
+        let inner = &handle.handle_inner;
+        inner.set_job_type(job_type.clone());
+        tracing::debug!(parent: &inner.span, "job output found in the result cache");
+        inner.hold();
+        inner.complete(Ok(output));
+        Some(handle)
+    }
+
+    /// Caches the output of the job once it completes
+    fn cache_output(&self, handle: &JobHandle<Value, Value>, job_type: Option<String>, x: Value) {
+        let (Some(cache), Some(job_type)) = (self.result_cache.clone(), job_type) else {
+            return;
+        };
+        handle.handle_inner.on_complete(Box::new(move |result| {
+            if let Ok(output) = result {
+                cache.insert(&job_type, &x, output);
+            }
+        }));
+    }
 }
 
-impl<X: Send + Sync + 'static, Y: Send + Sync + 'static> JobSystem<X, Y> {
-    pub(crate) fn add_worker(&mut self) {
-        self.workers.push(Worker::new(self.message_queue.clone()));
+/// Applies the job type and deadline of `options`. Workers enforce the deadline on running jobs, while the timer
+/// resolves jobs that are still waiting in the queue once it passes
+fn apply_options<X, Y>(
+    inner: &Arc<HandleInner<X, Y>>,
+    options: &JobOptions,
+    timer: &Timer,
+    queue: &Arc<WorkerQueue<X, Y>>,
+) where
+    X: Send + Sync + 'static,
+    Y: Send + Sync + 'static,
+{
+    if let Some(job_type) = &options.job_type {
+        inner.set_job_type(job_type.clone());
+    }
+    let Some(deadline) = options.deadline_from(Instant::now()) else {
+        return;
+    };
+    let _ = inner.deadline.set(deadline);
+    let (inner, queue) = (Arc::downgrade(inner), Arc::downgrade(queue));
+    timer.schedule(
+        deadline,
+        Box::new(move |fired| {
+            // Workers still check the deadline of jobs that are left once the timer is flushed
+            let (Fired::Due, Some(inner)) = (fired, inner.upgrade()) else {
+                return;
+            };
+            if inner.resolve_unstarted(JobError::TimedOut) {
+                if let Some(queue) = queue.upgrade() {
+                    queue.remove_where(&|queued| Arc::ptr_eq(queued, &inner));
+                }
+            }
+        }),
+    );
+}
+
+/// The state of a job sent through `send_job_every`, carried from one run to the next by the timer
+struct Recurrence<X: Send + Sync, Y: Send + Sync, F> {
+    x: X,
+    f: Arc<F>,
+    interval: Duration,
+    options: JobOptions,
+    previous: Mutex<Weak<HandleInner<X, Y>>>,
+    runs: Sender<JobHandle<X, Y>>,
+    stopped: Arc<AtomicBool>,
+    queue: Weak<WorkerQueue<X, Y>>,
+    type_limits: Arc<TypeLimits<X, Y>>,
+    timer: Weak<Timer>,
+    metrics: Arc<Metrics>,
+    system_id: u64,
+}
+
+impl<X, Y, F> Recurrence<X, Y, F>
+where
+    X: Clone + Send + Sync + 'static,
+    Y: Send + Sync + 'static,
+    F: Fn(X) -> Y + Send + Sync + 'static,
+{
+    fn schedule(self: Arc<Self>, timer: &Timer, at: Instant) {
+        timer.schedule(at, Box::new(move |fired| self.run(at, fired)));
+    }
+
+    fn run(self: Arc<Self>, at: Instant, fired: Fired) {
+        // Stopping drops the recurrence, and with it the sender, which ends the RecurringJob iterator
+        if self.stopped.load(Ordering::Relaxed) || fired == Fired::Flushed {
+            return;
+        }
+        let (Some(queue), Some(timer)) = (self.queue.upgrade(), self.timer.upgrade()) else {
+            return;
+        };
+        let mut previous = self.previous.lock().unwrap();
+        let busy = previous.upgrade().is_some_and(|inner| {
+            matches!(
+                *inner.status.lock().unwrap(),
+                Status::Pending | Status::Queued | Status::Running
+            )
+        });
+        if !busy {
+            let f = self.f.clone();
+            let handle = JobHandle::new(
+                self.x.clone(),
+                move |x| f(x),
+                Arc::downgrade(&queue),
+                self.metrics.clone(),
+                self.system_id,
+            );
+            *previous = Arc::downgrade(&handle.handle_inner);
+            apply_options(&handle.handle_inner, &self.options, &timer, &queue);
+            self.type_limits.send(
+                handle.handle_inner.clone(),
+                self.options.queue_name(),
+                self.options.priority,
+            );
+            if self.runs.send(handle).is_err() {
+                // Nobody is listening for runs anymore
+                return;
+            }
+        }
+        drop(previous);
+        let next = at + self.interval;
+        self.schedule(&timer, next);
+    }
+}
+
+impl<X: Send + Sync + 'static, Y: Send + Sync + 'static> Default for JobSystem<X, Y> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl<X: Send + Sync, Y: Send + Sync> Drop for JobSystem<X, Y> {
     fn drop(&mut self) {
-        for _ in 0..self.workers.len() {
-            self.message_queue.send(WorkerMessage::Join)
-        }
+        // Jobs waiting out a retry backoff are requeued right away, so they are not lost once the workers leave
+        self.timer.flush();
+        // Workers only leave once the queues they serve are empty, so every job queued before the drop still runs as
+        // long as some worker serves its queue
+        self.message_queue.join_all();
     }
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub mod ffi {
     use dashmap::DashMap;
     use lazy_static::lazy_static;
     use serde_json::{json, Value};
     use std::sync::atomic::Ordering::Relaxed;
     use std::{
+        collections::BTreeMap,
         ffi::{c_char, CStr, CString},
+        path::Path,
         str::FromStr,
-        sync::{atomic::AtomicU64, Mutex},
+        sync::{atomic::AtomicU64, Arc, Mutex, Once},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::system::{
+        job_context::Progress,
+        job_handle::{JobError, JobHandle, Status},
+        job_options::{JobOptions, Priority, RetryPolicy},
+        journal::{Journal, JournaledJob},
+        metrics::SystemStats,
+        result_cache::ResultCache,
     };
 
-    use crate::system::job_handle::{JobHandle, Status};
+    use super::{BatchRejected, JobSystem, JobSystemBuilder, Scheduler, ShutdownMode};
 
-    use super::JobSystem;
+    /// Error returned when a bounded queue has no room for a job
+    const QUEUE_FULL: &str = "queue_full";
+    /// Error of a send_jobs batch that is larger than the capacity of the queue, and so can never be sent whole
+    const BATCH_EXCEEDS_CAPACITY: &str = "batch_exceeds_capacity";
+
+    /// Results a result cache holds in memory when its "capacity" is not given
+    const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+    /// How often the sweeper drops expired results, so a result may outlive its TTL by up to this long
+    const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// How long the handle id of an expired result is still reported as "expired", after which it is forgotten
+    const EXPIRED_RETENTION: Duration = Duration::from_secs(5 * 60);
 
     type JobDef = fn(Value) -> Value;
     lazy_static! {
         static ref ID_COUNTER: AtomicU64 = AtomicU64::new(0);
         static ref JOB_MAP: DashMap<u64, JobHandle<Value, Value>> = DashMap::new();
         static ref SYSTEM_MAP: DashMap<u64, Mutex<JobSystem<Value, Value>>> = DashMap::new();
+        /// Handle ids of finished jobs per system that poll_completed has not reported yet, keyed by the order they
+        /// finished in. Holds an entry from the moment a system is created until it has shut down, and jobs finishing
+        /// after that are not recorded
+        static ref COMPLETED: DashMap<u64, BTreeMap<u64, u64>> = DashMap::new();
+        /// The system and key each handle id is listed under in COMPLETED, by handle id, so it can be dropped from there
+        /// once its handle leaves JOB_MAP
+        static ref LISTED: DashMap<u64, (u64, u64)> = DashMap::new();
+        static ref COMPLETION_COUNTER: AtomicU64 = AtomicU64::new(0);
+        /// Journals of the systems created with one, by system id
+        static ref JOURNALS: DashMap<u64, Arc<Journal>> = DashMap::new();
+        /// The journal each journaled job is recorded in, by handle id, until its result is handed out
+        static ref JOURNALED_JOBS: DashMap<u64, Arc<Journal>> = DashMap::new();
+        /// How long the results of finished jobs are kept, for the systems created with a "result_ttl_ms"
+        static ref RESULT_TTLS: DashMap<u64, Duration> = DashMap::new();
+        /// When the result of each finished job of those systems expires, by handle id
+        static ref EXPIRY: DashMap<u64, Instant> = DashMap::new();
+        /// The number of attempts of each job whose handle was dropped by the sweeper, and when the sweeper forgets it,
+        /// by handle id
+        static ref EXPIRED: DashMap<u64, (u32, Instant)> = DashMap::new();
         static ref JOB_KV: DashMap<String, JobDef> = {
             let map = DashMap::new();
             map.insert("make".into(), crate::jobs::make::output as JobDef);
@@ -118,10 +1054,191 @@ pub mod ffi {
         }};
     }
 
+    #[no_mangle]
+    /// Returns the "stats" of the system given by "system_id": current "queued" and "running" jobs, "completed", "failed"
+    /// and "cancelled" totals, the "queue_wait" histogram, per job type counts and "run_time" histograms under
+    /// "job_types", and the "utilization" of its workers
+    pub extern "C" fn get_system_stats(json_str_ptr: *const c_char) -> *const c_char {
+        let output_json = if json_str_ptr.is_null() {
+            json!({"error" : "json_str_ptr was a null pointer"})
+        } else {
+            let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
+
+            match query_system_stats(input_str) {
+                Ok(stats) => json!({"success" : true, "stats" : stats}),
+                Err(message) => json!({"success" : false, "error" : message}),
+            }
+        };
+        into_raw_cstr!(output_json)
+    }
+
+    fn query_system_stats(input_str: &str) -> Result<SystemStats, String> {
+        let job_json = parse_json_from_str!(input_str)?;
+
+        let system = fetch_system_from_json!(job_json)?;
+        let system = system.lock().unwrap();
+
+        Ok(system.stats())
+    }
+
+    #[no_mangle]
+    /// Creates a system configured by the JSON: an optional number of "workers" to start with, an optional
+    /// "capacity" bounding its queue, and an optional "scheduler", either "shared_queue" or "work_stealing".
+    /// An optional "queues" object maps queue names to the number of workers serving only that queue, on top of the
+    /// "workers" serving the default queue.
+    /// An optional "type_limits" object maps job types to the most jobs of that type that may run at once.
+    /// An optional "journal" path records every job sent to the system on disk. A system created with the path of an
+    /// existing journal sends the jobs in it that never finished again, and restores the results of those that did,
+    /// all under their original handle ids. Jobs that cannot be sent again finish with the status "replay_failed". The
+    /// journal is compacted to the jobs whose results were not handed out yet whenever it has doubled in size.
+    /// An optional "cache" object gives the system a result cache for jobs sent with "cache" set, holding up to its
+    /// optional "capacity" results in memory, and keeping them on disk as well if it has a "path" to a directory.
+    /// An optional "result_ttl_ms" drops the handles of finished jobs that long after they finish, unless their result
+    /// was collected through get_job first. Their status is reported as "expired" for five minutes after that, after
+    /// which their handle ids are no longer known
+    pub extern "C" fn create_jobsystem_with_config(json_str_ptr: *const c_char) -> *const c_char {
+        let output_json = if json_str_ptr.is_null() {
+            json!({"error" : "json_str_ptr was a null pointer"})
+        } else {
+            let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
+
+            match process_and_create_system(input_str) {
+                Ok(system_id) => json!({"success" : true, "system_id" : system_id}),
+                Err(message) => json!({"success" : false, "error" : message}),
+            }
+        };
+        into_raw_cstr!(output_json)
+    }
+
+    fn process_and_create_system(input_str: &str) -> Result<u64, String> {
+        let config_json = parse_json_from_str!(input_str)?;
+
+        let mut builder = JobSystemBuilder::new();
+        if let Some(workers) = config_json["workers"].as_u64() {
+            builder = builder.workers(workers as usize);
+        }
+        if let Some(capacity) = config_json["capacity"].as_u64() {
+            builder = builder.capacity(capacity as usize);
+        }
+        if let Some(queues) = config_json.get("queues") {
+            let queues = queues
+                .as_object()
+                .ok_or("'queues' must map queue names to numbers of workers")?;
+            for (queue, workers) in queues {
+                let workers = workers
+                    .as_u64()
+                    .ok_or("'queues' must map queue names to numbers of workers")?;
+                builder = builder.workers_for(&[queue], workers as usize);
+            }
+        }
+        if let Some(limits) = config_json.get("type_limits") {
+            let limits = limits
+                .as_object()
+                .ok_or("'type_limits' must map job types to numbers of jobs")?;
+            for (job_type, limit) in limits {
+                let limit = limit
+                    .as_u64()
+                    .ok_or("'type_limits' must map job types to numbers of jobs")?;
+                builder = builder.type_limit(job_type, limit as usize);
+            }
+        }
+        if let Some(cache_json) = config_json.get("cache") {
+            let capacity = cache_json["capacity"]
+                .as_u64()
+                .map_or(DEFAULT_CACHE_CAPACITY, |capacity| capacity as usize);
+            let cache = match cache_json["path"].as_str() {
+                None => ResultCache::new(capacity),
+                Some(path) => ResultCache::with_store(capacity, path)
+                    .map_err(|e| format!("unable to open cache store '{}': {}", path, e))?,
+            };
+            builder = builder.result_cache(cache);
+        }
+        match config_json["scheduler"].as_str() {
+            None | Some("shared_queue") => {}
+            Some("work_stealing") => builder = builder.scheduler(Scheduler::WorkStealing),
+            Some(other) => return Err(format!("scheduler '{}' is not known", other)),
+        }
+
+        let journal = match config_json["journal"].as_str() {
+            None => None,
+            Some(path) => Some(
+                Journal::open(Path::new(path))
+                    .map_err(|e| format!("unable to open journal '{}': {}", path, e))?,
+            ),
+        };
+        if let Some(max_id) = journal
+            .iter()
+            .flat_map(|(_, jobs)| jobs)
+            .map(|j| j.id)
+            .max()
+        {
+            // Handle ids given out from now on must not collide with the ones being restored
+            ID_COUNTER.fetch_max(max_id + 1, Relaxed);
+        }
+
+        let id = ID_COUNTER.fetch_add(1, Relaxed);
+        let mut system = builder.build();
+        COMPLETED.insert(id, BTreeMap::new());
+        if let Some(ttl) = config_json["result_ttl_ms"].as_u64() {
+            RESULT_TTLS.insert(id, Duration::from_millis(ttl));
+            start_sweeper();
+        }
+        if let Some((journal, jobs)) = journal {
+            JOURNALS.insert(id, Arc::new(journal));
+            replay_journal(&mut system, id, jobs);
+        }
+        SYSTEM_MAP.insert(id, Mutex::new(system));
+        Ok(id)
+    }
+
+    /// Sends the journaled jobs that never finished again and restores the results of the others
+    fn replay_journal(
+        system: &mut JobSystem<Value, Value>,
+        system_id: u64,
+        jobs: Vec<JournaledJob>,
+    ) {
+        // The jobs were all accepted before the restart, so they are sent even if they do not fit in a bounded queue.
+        // Nothing else can send to the system yet, as it is not in SYSTEM_MAP
+        let capacity = system.capacity.take();
+        for job in jobs {
+            if JOB_MAP.contains_key(&job.id) {
+                tracing::warn!(handle_id = job.id, "Journaled job id is already taken");
+                continue;
+            }
+            match job.result {
+                Some(result) => {
+                    let handle = system.restore_job(job.request["input"].clone(), result);
+                    JOURNALED_JOBS.insert(job.id, JOURNALS.get(&system_id).unwrap().clone());
+                    if let Some(ttl) = result_ttl(system_id) {
+                        EXPIRY.insert(job.id, Instant::now() + ttl);
+                    }
+                    JOB_MAP.insert(job.id, handle);
+                    record_completed(system_id, job.id);
+                }
+                None => match load_job(system, &job.request) {
+                    Ok(handle) => insert_job(system_id, job.id, handle),
+                    Err(message) => {
+                        tracing::warn!(
+                            handle_id = job.id,
+                            "Unable to replay journaled job: {}",
+                            message
+                        );
+                        // Kept as a failed job, so the host still learns what became of it
+                        let error = JobError::ReplayFailed(message);
+                        let handle = system.restore_job(job.request["input"].clone(), Err(error));
+                        insert_job(system_id, job.id, handle);
+                    }
+                },
+            }
+        }
+        system.capacity = capacity;
+    }
+
     #[no_mangle]
     pub extern "C" fn create_jobsystem() -> *const c_char {
         let system = Mutex::new(JobSystem::new());
         let id = ID_COUNTER.fetch_add(1, Relaxed);
+        COMPLETED.insert(id, BTreeMap::new());
         SYSTEM_MAP.insert(id, system);
         let output_json = json!({"success" : true, "system_id" : id});
 
@@ -130,6 +1247,9 @@ pub mod ffi {
     }
 
     #[no_mangle]
+    /// Shuts down the system given by "system_id". The optional "mode" is either "drain", the default, which runs the
+    /// queued jobs first, or "abort", which cancels them. Once the optional "deadline_ms" passes, the jobs still running
+    /// are cancelled, and "timed_out" is true
     pub extern "C" fn destroy_jobsystem(json_str_ptr: *const c_char) -> *const c_char {
         let output_json = if json_str_ptr.is_null() {
             json!({"error" : "json_str_ptr was a null pointer"})
@@ -137,25 +1257,43 @@ pub mod ffi {
             let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
 
             match remove_system_from_map(input_str) {
-                Ok(()) => json!({"success" : true}),
+                Ok(finished) => json!({"success" : true, "timed_out" : !finished}),
                 Err(message) => json!({"success" : false, "error" : message}),
             }
         };
         into_raw_cstr!(output_json)
     }
 
-    fn remove_system_from_map(input_str: &str) -> Result<(), String> {
+    fn remove_system_from_map(input_str: &str) -> Result<bool, String> {
         let system_json = parse_json_from_str!(input_str)?;
         let system_id = system_json["system_id"]
             .as_u64()
             .ok_or("'system_id' key is not a valid number or may not exist")?;
-        SYSTEM_MAP
+        let mode = match system_json["mode"].as_str() {
+            None | Some("drain") => ShutdownMode::Drain,
+            Some("abort") => ShutdownMode::Abort,
+            Some(other) => return Err(format!("shutdown mode '{}' is not known", other)),
+        };
+        let deadline = system_json["deadline_ms"]
+            .as_u64()
+            .map(Duration::from_millis);
+        let (_, system) = SYSTEM_MAP
             .remove(&system_id)
             .ok_or("specified system id was not found")?;
-        Ok(())
+        JOURNALS.remove(&system_id);
+        let finished = system.into_inner().unwrap().shutdown(mode, deadline);
+        // Only dropped once the jobs drained by the shutdown have been recorded
+        if let Some((_, completed)) = COMPLETED.remove(&system_id) {
+            for handle_id in completed.values() {
+                LISTED.remove(handle_id);
+            }
+        }
+        RESULT_TTLS.remove(&system_id);
+        Ok(finished)
     }
 
     #[no_mangle]
+    /// Adds a worker to the system given by "system_id", serving the optional array of "queues", or the default queue
     pub extern "C" fn add_worker(json_str_ptr: *const c_char) -> *const c_char {
         let output_json = if json_str_ptr.is_null() {
             json!({"error" : "json_str_ptr was a null pointer"})
@@ -173,41 +1311,267 @@ pub mod ffi {
     fn query_system_add_worker(input_str: &str) -> Result<(), String> {
         let job_json = parse_json_from_str!(input_str)?;
 
+        let queues = match &job_json["queues"] {
+            Value::Null => Vec::new(),
+            queues => queue_names(queues)?,
+        };
+
         let system = fetch_system_from_json!(job_json)?;
         let mut system = system.lock().unwrap();
 
-        system.add_worker();
+        system.add_worker_for(&queues);
         Ok(())
     }
 
+    fn queue_names(queues: &Value) -> Result<Vec<&str>, String> {
+        queues
+            .as_array()
+            .and_then(|queues| queues.iter().map(Value::as_str).collect())
+            .ok_or("'queues' must be an array of queue names".into())
+    }
+
     #[no_mangle]
-    pub extern "C" fn get_job(json_str_ptr: *const c_char) -> *const c_char {
+    /// Retires one worker of the system given by "system_id" once it is idle. The last worker serving a named queue is
+    /// never removed
+    pub extern "C" fn remove_worker(json_str_ptr: *const c_char) -> *const c_char {
         let output_json = if json_str_ptr.is_null() {
             json!({"error" : "json_str_ptr was a null pointer"})
         } else {
             let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
 
-            match process_and_query_job(input_str) {
-                Ok(handle_id) => json!({"success" : true, "handle_id" : handle_id}),
+            match query_system_remove_worker(input_str) {
+                Ok(()) => json!({"success" : true}),
                 Err(message) => json!({"success" : false, "error" : message}),
             }
         };
         into_raw_cstr!(output_json)
     }
 
-    fn process_and_query_job(input_str: &str) -> Result<Value, String> {
+    fn query_system_remove_worker(input_str: &str) -> Result<(), String> {
         let job_json = parse_json_from_str!(input_str)?;
 
-        let handle_id = job_json["handle_id"]
-            .as_u64()
-            .ok_or("'type' handle_id is not a valid number or may not exist")?;
+        let system = fetch_system_from_json!(job_json)?;
+        let mut system = system.lock().unwrap();
+
+        if system.remove_worker() {
+            Ok(())
+        } else {
+            Err(
+                "system has no workers to remove, other than the last ones serving their queues"
+                    .into(),
+            )
+        }
+    }
+
+    #[no_mangle]
+    /// Grows or shrinks the system given by "system_id" to "count" workers, returning the resulting "workers". That may
+    /// be more than "count", as the last worker serving a named queue is never removed
+    pub extern "C" fn set_worker_count(json_str_ptr: *const c_char) -> *const c_char {
+        let output_json = if json_str_ptr.is_null() {
+            json!({"error" : "json_str_ptr was a null pointer"})
+        } else {
+            let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
+
+            match query_system_set_worker_count(input_str) {
+                Ok(workers) => json!({"success" : true, "workers" : workers}),
+                Err(message) => json!({"success" : false, "error" : message}),
+            }
+        };
+        into_raw_cstr!(output_json)
+    }
+
+    fn query_system_set_worker_count(input_str: &str) -> Result<usize, String> {
+        let job_json = parse_json_from_str!(input_str)?;
+
+        let count = job_json["count"]
+            .as_u64()
+            .ok_or("'count' key is not a valid number or may not exist")?;
+
+        let system = fetch_system_from_json!(job_json)?;
+        let mut system = system.lock().unwrap();
+
+        system.resize(count as usize);
+        Ok(system.worker_count())
+    }
 
-        let handle = JOB_MAP
+    #[no_mangle]
+    /// Blocks until the job given by "handle_id" finishes and returns its result. An optional "timeout_ms" bounds the
+    /// wait. The handle stays valid for other calls while the wait lasts, e.g. to cancel the job, and after a timeout
+    pub extern "C" fn get_job(json_str_ptr: *const c_char) -> *const c_char {
+        let output_json = if json_str_ptr.is_null() {
+            json!({"error" : "json_str_ptr was a null pointer"})
+        } else {
+            let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
+
+            match process_and_query_job(input_str) {
+                Ok(handle_id) => json!({"success" : true, "handle_id" : handle_id}),
+                Err(message) => json!({"success" : false, "error" : message}),
+            }
+        };
+        into_raw_cstr!(output_json)
+    }
+
+    fn process_and_query_job(input_str: &str) -> Result<Value, String> {
+        let job_json = parse_json_from_str!(input_str)?;
+
+        let handle_id = job_json["handle_id"]
+            .as_u64()
+            .ok_or("'type' handle_id is not a valid number or may not exist")?;
+
+        if EXPIRED.contains_key(&handle_id) {
+            return Err("the result of the job has expired".into());
+        }
+        // Waited on outside the map, so the handle stays reachable by its id, e.g. to cancel it, until its result is
+        // handed out
+        let inner = JOB_MAP
+            .get(&handle_id)
+            .map(|e| e.handle_inner.clone())
+            .ok_or("specified handle id was not found")?;
+        let until = job_json["timeout_ms"]
+            .as_u64()
+            .map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms));
+        if !inner.wait_for_result(until) {
+            return Err("timed out waiting for job".into());
+        }
+        // Another call may have collected the result in the meantime
+        let (_, handle) = JOB_MAP
             .remove(&handle_id)
-            .map(|e| e.1)
             .ok_or("specified handle id was not found")?;
+        let result = handle.get();
+        forget_completed(handle_id);
+        release_journaled(handle_id);
+        result.map_err(|e| e.to_string())
+    }
 
-        Ok(handle.get())
+    /// Lists a finished job for poll_completed, unless its system is gone or its handle already left JOB_MAP
+    fn record_completed(system_id: u64, handle_id: u64) {
+        let key = COMPLETION_COUNTER.fetch_add(1, Relaxed);
+        let Some(mut completed) = COMPLETED.get_mut(&system_id) else {
+            return;
+        };
+        completed.insert(key, handle_id);
+        drop(completed);
+        LISTED.insert(handle_id, (system_id, key));
+        // Released while it was being listed, in which case the release found nothing to drop yet
+        if !JOB_MAP.contains_key(&handle_id) {
+            forget_completed(handle_id);
+        }
+    }
+
+    /// Drops the job from the ids poll_completed reports, once its handle left JOB_MAP
+    fn forget_completed(handle_id: u64) {
+        if let Some((_, (system_id, key))) = LISTED.remove(&handle_id) {
+            if let Some(mut completed) = COMPLETED.get_mut(&system_id) {
+                completed.remove(&key);
+            }
+        }
+    }
+
+    /// Drops the job from the journal of its system, once its result was handed out or it was released
+    fn release_journaled(handle_id: u64) {
+        if let Some((_, journal)) = JOURNALED_JOBS.remove(&handle_id) {
+            journal.released(handle_id);
+        }
+    }
+
+    #[no_mangle]
+    /// Drops the handle given by "handle_id" without waiting for its result. A job that has not finished yet still
+    /// runs, but its result is discarded
+    pub extern "C" fn release_job(json_str_ptr: *const c_char) -> *const c_char {
+        let output_json = if json_str_ptr.is_null() {
+            json!({"error" : "json_str_ptr was a null pointer"})
+        } else {
+            let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
+
+            match process_and_release_job(input_str) {
+                Ok(()) => json!({"success" : true}),
+                Err(message) => json!({"success" : false, "error" : message}),
+            }
+        };
+        into_raw_cstr!(output_json)
+    }
+
+    fn process_and_release_job(input_str: &str) -> Result<(), String> {
+        let job_json = parse_json_from_str!(input_str)?;
+
+        let handle_id = job_json["handle_id"]
+            .as_u64()
+            .ok_or("'type' handle_id is not a valid number or may not exist")?;
+
+        JOB_MAP
+            .remove(&handle_id)
+            .ok_or("specified handle id was not found")?;
+        EXPIRY.remove(&handle_id);
+        forget_completed(handle_id);
+        release_journaled(handle_id);
+        Ok(())
+    }
+
+    fn result_ttl(system_id: u64) -> Option<Duration> {
+        RESULT_TTLS.get(&system_id).map(|ttl| *ttl)
+    }
+
+    /// Starts the thread that drops expired results, unless it is already running
+    fn start_sweeper() {
+        static SWEEPER: Once = Once::new();
+        SWEEPER.call_once(|| {
+            thread::spawn(|| loop {
+                thread::sleep(SWEEP_INTERVAL);
+                sweep(Instant::now());
+            });
+        });
+    }
+
+    /// Drops the handles whose results expired by `now`, remembering them as expired for `EXPIRED_RETENTION`
+    fn sweep(now: Instant) {
+        EXPIRED.retain(|_, (_, forget_at)| *forget_at > now);
+        // Collected first, as removing from a DashMap while iterating over it deadlocks
+        let due: Vec<u64> = EXPIRY
+            .iter()
+            .filter(|expiry| *expiry.value() <= now)
+            .map(|expiry| *expiry.key())
+            .collect();
+        for handle_id in due {
+            EXPIRY.remove(&handle_id);
+            // Handles collected by get_job or released in the meantime are already gone
+            if let Some((_, handle)) = JOB_MAP.remove(&handle_id) {
+                EXPIRED.insert(handle_id, (handle.attempts(), now + EXPIRED_RETENTION));
+                forget_completed(handle_id);
+                release_journaled(handle_id);
+                tracing::debug!(parent: &handle.handle_inner.span, handle_id, "job result expired");
+            }
+        }
+    }
+
+    #[no_mangle]
+    /// Cancels the job given by "handle_id". Queued jobs are dropped from the queue, running jobs are asked to stop
+    pub extern "C" fn cancel_job(json_str_ptr: *const c_char) -> *const c_char {
+        let output_json = if json_str_ptr.is_null() {
+            json!({"error" : "json_str_ptr was a null pointer"})
+        } else {
+            let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
+
+            match process_and_cancel_job(input_str) {
+                Ok(cancelled) => json!({"success" : true, "cancelled" : cancelled}),
+                Err(message) => json!({"success" : false, "error" : message}),
+            }
+        };
+        into_raw_cstr!(output_json)
+    }
+
+    fn process_and_cancel_job(input_str: &str) -> Result<bool, String> {
+        let job_json = parse_json_from_str!(input_str)?;
+
+        let handle_id = job_json["handle_id"]
+            .as_u64()
+            .ok_or("'type' handle_id is not a valid number or may not exist")?;
+
+        let cancelled = JOB_MAP
+            .get(&handle_id)
+            .map(|e| e.cancel())
+            .ok_or("specified handle id was not found")?;
+
+        Ok(cancelled)
     }
 
     #[no_mangle]
@@ -218,7 +1582,9 @@ pub mod ffi {
             let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
 
             match process_and_query_job_status(input_str) {
-                Ok(status) => json!({"success" : true, "status" : status}),
+                Ok((status, attempts)) => {
+                    json!({"success" : true, "status" : status, "attempts" : attempts})
+                }
                 Err(message) => json!({"success" : false, "error" : message}),
             }
         };
@@ -226,29 +1592,87 @@ pub mod ffi {
         into_raw_cstr!(output_json)
     }
 
-    fn process_and_query_job_status(input_str: &str) -> Result<Value, String> {
+    fn process_and_query_job_status(input_str: &str) -> Result<(Value, u32), String> {
         let job_json = parse_json_from_str!(input_str)?;
 
         let handle_id = job_json["handle_id"]
             .as_u64()
             .ok_or("'type' handle_id is not a valid number or may not exist")?;
 
-        let status = JOB_MAP
+        if let Some(expired) = EXPIRED.get(&handle_id) {
+            return Ok(("expired".into(), expired.0));
+        }
+        let (status, attempts) = JOB_MAP
             .get(&handle_id)
-            .map(|e| e.get_status())
+            .map(|e| (e.get_status(), e.attempts()))
             .ok_or("specified handle id was not found")?;
 
-        let status_str = match status {
+        Ok((status_str(status).into(), attempts))
+    }
+
+    fn status_str(status: Status) -> &'static str {
+        match status {
+            Status::Pending => "pending",
             Status::Queued => "queued",
             Status::Running => "running",
             Status::Completed => "completed",
+            Status::Cancelled => "cancelled",
+            Status::Panicked => "panicked",
+            Status::TimedOut => "timed_out",
+            Status::ReplayFailed => "replay_failed",
+        }
+    }
+
+    #[no_mangle]
+    /// Returns the progress of the job given by "handle_id": the last "percent" it reported, and the "updates" it emitted
+    /// since the previous call
+    pub extern "C" fn get_job_progress(json_str_ptr: *const c_char) -> *const c_char {
+        let output_json = if json_str_ptr.is_null() {
+            json!({"error" : "json_str_ptr was a null pointer"})
+        } else {
+            let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
+
+            match process_and_query_job_progress(input_str) {
+                Ok((status, progress)) => json!({
+                    "success" : true,
+                    "status" : status_str(status),
+                    "percent" : progress.percent,
+                    "updates" : progress.updates
+                }),
+                Err(message) => json!({"success" : false, "error" : message}),
+            }
         };
 
-        Ok(status_str.into())
+        into_raw_cstr!(output_json)
+    }
+
+    fn process_and_query_job_progress(input_str: &str) -> Result<(Status, Progress), String> {
+        let job_json = parse_json_from_str!(input_str)?;
+
+        let handle_id = job_json["handle_id"]
+            .as_u64()
+            .ok_or("'type' handle_id is not a valid number or may not exist")?;
+
+        let progress = JOB_MAP
+            .get(&handle_id)
+            .map(|e| (e.get_status(), e.take_progress()))
+            .ok_or("specified handle id was not found")?;
+
+        Ok(progress)
     }
 
     #[no_mangle]
     /// Sends the specified command to the JobSystem, given a JSON with key "type", specifying jobtype and "input", specifying the input data for the job.
+    /// An optional integer "priority" lets the job skip ahead of lower priority work.
+    /// An optional "queue" name sends the job to that queue rather than the default one, and fails if no worker
+    /// serves it.
+    /// An optional "deadline_ms" resolves the job as timed out if it has not finished that long after it was sent.
+    /// An optional "after" array of handle ids holds the job back until those jobs complete. With "pass_outputs" set, their
+    /// results are passed to the job under the "dependencies" key of its input.
+    /// An optional "retry" object runs failed attempts again, see parse_retry_policy.
+    /// With "cache" set, a system created with a result cache resolves the job right away if a job of the same type
+    /// already completed on an equal input, and caches the output of the job otherwise.
+    /// If the system has a capacity and its queue is full, fails with the error "queue_full" instead of waiting
     pub extern "C" fn send_job(json_str_ptr: *const c_char) -> *const c_char {
         let output_json = if json_str_ptr.is_null() {
             json!({"error" : "json_str_ptr was a null pointer"})
@@ -268,7 +1692,22 @@ pub mod ffi {
         let job_json = parse_json_from_str!(input_str)?;
 
         let system = fetch_system_from_json!(job_json)?;
+        let system_id = *system.key();
 
+        let mut system = system.lock().unwrap();
+        let handle = load_job(&mut system, &job_json)?;
+        let id = ID_COUNTER.fetch_add(1, Relaxed);
+        journal_submission(system_id, id, &job_json);
+        insert_job(system_id, id, handle);
+
+        Ok(id)
+    }
+
+    /// Sends the job described by a send_job request to `system`
+    fn load_job(
+        system: &mut JobSystem<Value, Value>,
+        job_json: &Value,
+    ) -> Result<JobHandle<Value, Value>, String> {
         let job_type = job_json["type"]
             .as_str()
             .ok_or("'type' key is not a string or may not exist")?;
@@ -277,13 +1716,328 @@ pub mod ffi {
 
         let job_fn = job.ok_or(format!("job type '{}' was not found", job_type))?;
 
-        let id = ID_COUNTER.fetch_add(1, Relaxed);
+        let options = parse_job_options(job_json);
+        check_queue(system, &options)?;
+        let retry = parse_retry_policy(job_json)?;
+
         let input = job_json["input"].clone();
-        let mut system = system.lock().unwrap();
-        let handle = system.send_job(input, job_fn);
+        let cached = job_json["cache"].as_bool().unwrap_or(false);
+        if cached {
+            if job_json["after"].is_array() {
+                return Err("'cache' cannot be combined with 'after'".into());
+            }
+            if let Some(handle) = system.cached_job(&input, &options) {
+                return Ok(handle);
+            }
+        }
+        let job_type = options.job_type.clone();
+        let handle = match (job_json["after"].as_array(), retry) {
+            (None, None) => system
+                .try_send_job_with(input, job_fn, options)
+                .map_err(|_| QUEUE_FULL)?,
+            (None, Some(policy)) => {
+                if system.wait_for_room(1, Some(Instant::now())).is_none() {
+                    return Err(QUEUE_FULL.into());
+                }
+                system.send_job_with_retry(input, job_fn, options, policy)
+            }
+            (Some(_), Some(_)) => return Err("'retry' cannot be combined with 'after'".into()),
+            (Some(after), None) => {
+                // The map guards must be released before the new handle is inserted, as they may share a shard
+                let deps = after
+                    .iter()
+                    .map(|id| {
+                        let id = id
+                            .as_u64()
+                            .ok_or("'after' must be an array of handle ids")?;
+                        JOB_MAP
+                            .get(&id)
+                            .ok_or(format!("dependency handle id {} was not found", id))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                let deps: Vec<_> = deps.iter().map(|dep| dep.value()).collect();
+
+                if job_json["pass_outputs"].as_bool().unwrap_or(false) {
+                    if !input.is_object() {
+                        return Err("'input' must be an object when 'pass_outputs' is set".into());
+                    }
+                    system.send_job_after_with_outputs(
+                        &deps,
+                        input,
+                        move |mut input, outputs| {
+                            let outputs: Vec<Value> = outputs
+                                .into_iter()
+                                .map(|output| {
+                                    output.unwrap_or_else(|e| json!({"error" : e.to_string()}))
+                                })
+                                .collect();
+                            input["dependencies"] = outputs.into();
+                            job_fn(input)
+                        },
+                        options,
+                    )
+                } else {
+                    system.send_job_after_with(&deps, input, job_fn, options)
+                }
+            }
+        };
+        if cached {
+            system.cache_output(&handle, job_type, job_json["input"].clone());
+        }
+        Ok(handle)
+    }
+
+    /// Records the send_job request of a new job in the journal of its system, if it has one. Called before the handle
+    /// id is handed out, so every job a caller knows about can be replayed
+    fn journal_submission(system_id: u64, id: u64, job_json: &Value) {
+        if let Some(journal) = JOURNALS.get(&system_id) {
+            let mut request = job_json.clone();
+            if let Some(request) = request.as_object_mut() {
+                request.remove("system_id");
+            }
+            journal.submitted(id, &request);
+        }
+    }
+
+    /// Makes the handle reachable by its id, and reports the id through poll_completed once the job finishes, for as
+    /// long as the handle is still reachable. The outcome is also recorded in the journal of the system, if it has one,
+    /// and expires after its result TTL
+    fn insert_job(system_id: u64, id: u64, handle: JobHandle<Value, Value>) {
+        tracing::debug!(
+            parent: &handle.handle_inner.span,
+            handle_id = id,
+            system_id,
+            "job registered with the FFI"
+        );
+        let journal = JOURNALS.get(&system_id).map(|journal| journal.clone());
+        if let Some(journal) = &journal {
+            JOURNALED_JOBS.insert(id, journal.clone());
+        }
+        let ttl = result_ttl(system_id);
+        let inner = handle.handle_inner.clone();
+        // Inserted first, as a job that already finished runs the callback right away
         JOB_MAP.insert(id, handle);
+        inner.on_complete(Box::new(move |result| {
+            if let Some(journal) = journal {
+                journal.finished(id, result);
+            }
+            if let Some(ttl) = ttl {
+                EXPIRY.insert(id, Instant::now() + ttl);
+            }
+            record_completed(system_id, id);
+        }));
+    }
 
-        Ok(id)
+    #[no_mangle]
+    /// Sends one job of the given "type" for every element of the "inputs" array, returning the "handle_ids" in the same
+    /// order. Accepts the same optional "priority", "deadline_ms" and "queue" as send_job.
+    /// Fails with "queue_full" without sending any job if the batch does not fit in a bounded queue right now, or with
+    /// "batch_exceeds_capacity" if it holds more jobs than the capacity of the queue, in which case it never fits
+    pub extern "C" fn send_jobs(json_str_ptr: *const c_char) -> *const c_char {
+        let output_json = if json_str_ptr.is_null() {
+            json!({"error" : "json_str_ptr was a null pointer"})
+        } else {
+            let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
+
+            match process_and_load_jobs(input_str) {
+                Ok(handle_ids) => json!({"success" : true, "handle_ids" : handle_ids}),
+                Err(message) => json!({"success" : false, "error" : message}),
+            }
+        };
+
+        into_raw_cstr!(output_json)
+    }
+
+    fn process_and_load_jobs(input_str: &str) -> Result<Vec<u64>, String> {
+        let job_json = parse_json_from_str!(input_str)?;
+
+        let system = fetch_system_from_json!(job_json)?;
+        let system_id = *system.key();
+
+        let job_type = job_json["type"]
+            .as_str()
+            .ok_or("'type' key is not a string or may not exist")?;
+
+        let job_fn =
+            map_job_identifier(job_type).ok_or(format!("job type '{}' was not found", job_type))?;
+
+        let inputs = job_json["inputs"]
+            .as_array()
+            .ok_or("'inputs' key is not an array or may not exist")?
+            .clone();
+
+        let options = parse_job_options(&job_json);
+
+        let mut system = system.lock().unwrap();
+        check_queue(&system, &options)?;
+        let handles = system
+            .try_map_with(inputs, job_fn, options)
+            .map_err(|e| match e {
+                BatchRejected::QueueFull(_) => QUEUE_FULL,
+                BatchRejected::ExceedsCapacity(_) => BATCH_EXCEEDS_CAPACITY,
+            })?;
+        let first_id = ID_COUNTER.fetch_add(handles.len() as u64, Relaxed);
+        let ids: Vec<u64> = (first_id..first_id + handles.len() as u64).collect();
+        // Each job is journaled as the send_job request that sends its one input
+        let mut request = job_json.clone();
+        if let Some(request) = request.as_object_mut() {
+            request.remove("inputs");
+        }
+        for (i, (&id, handle)) in ids.iter().zip(handles).enumerate() {
+            request["input"] = job_json["inputs"][i].clone();
+            journal_submission(system_id, id, &request);
+            insert_job(system_id, id, handle);
+        }
+
+        Ok(ids)
+    }
+
+    fn parse_job_options(job_json: &Value) -> JobOptions {
+        let mut options = JobOptions::new();
+        if let Some(job_type) = job_json["type"].as_str() {
+            options = options.job_type(job_type);
+        }
+        if let Some(priority) = job_json["priority"].as_i64() {
+            options = options
+                .priority(priority.clamp(Priority::MIN.into(), Priority::MAX.into()) as Priority);
+        }
+        if let Some(deadline_ms) = job_json["deadline_ms"].as_u64() {
+            options = options.timeout(Duration::from_millis(deadline_ms));
+        }
+        if let Some(queue) = job_json["queue"].as_str() {
+            options = options.queue(queue);
+        }
+        options
+    }
+
+    /// Fails if the job goes to a named queue that no worker of `system` serves, where it would never run
+    fn check_queue(system: &JobSystem<Value, Value>, options: &JobOptions) -> Result<(), String> {
+        let queue = options.queue_name();
+        if system.serves_queue(queue) {
+            Ok(())
+        } else {
+            Err(format!("queue '{}' is not served by any worker", queue))
+        }
+    }
+
+    /// Reads the optional "retry" object of a job: "max_attempts" (required), "backoff_ms", "multiplier" and
+    /// "max_backoff_ms". An attempt is retried if it panicked or returned a nonzero "status", or only for the statuses
+    /// listed in "on_status" if given
+    fn parse_retry_policy(job_json: &Value) -> Result<Option<RetryPolicy<Value>>, String> {
+        let retry = &job_json["retry"];
+        if retry.is_null() {
+            return Ok(None);
+        }
+        let max_attempts = retry["max_attempts"]
+            .as_u64()
+            .ok_or("'retry' must contain a 'max_attempts' number")?;
+        let mut policy = RetryPolicy::<Value>::new(max_attempts.min(u32::MAX.into()) as u32);
+        if let Some(backoff_ms) = retry["backoff_ms"].as_u64() {
+            policy = policy.backoff(Duration::from_millis(backoff_ms));
+        }
+        if let Some(multiplier) = retry["multiplier"].as_f64() {
+            policy = policy.multiplier(multiplier);
+        }
+        if let Some(max_backoff_ms) = retry["max_backoff_ms"].as_u64() {
+            policy = policy.max_backoff(Duration::from_millis(max_backoff_ms));
+        }
+        let on_status = match retry["on_status"].as_array() {
+            Some(statuses) => Some(
+                statuses
+                    .iter()
+                    .map(|s| s.as_i64().ok_or("'on_status' must be an array of numbers"))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+        Ok(Some(policy.retry_if(move |result| match result {
+            Err(_) => true,
+            Ok(output) => {
+                let status = output["status"].as_i64().unwrap_or(0);
+                match &on_status {
+                    Some(statuses) => statuses.contains(&status),
+                    None => status != 0,
+                }
+            }
+        })))
+    }
+
+    #[no_mangle]
+    /// Returns the "handle_ids" of the jobs of the system given by "system_id" that finished since the previous call,
+    /// in the order they finished. Jobs whose result was collected or released before the call are left out
+    pub extern "C" fn poll_completed(json_str_ptr: *const c_char) -> *const c_char {
+        let output_json = if json_str_ptr.is_null() {
+            json!({"error" : "json_str_ptr was a null pointer"})
+        } else {
+            let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
+
+            match process_and_poll_completed(input_str) {
+                Ok(handle_ids) => json!({"success" : true, "handle_ids" : handle_ids}),
+                Err(message) => json!({"success" : false, "error" : message}),
+            }
+        };
+
+        into_raw_cstr!(output_json)
+    }
+
+    fn process_and_poll_completed(input_str: &str) -> Result<Vec<u64>, String> {
+        let job_json = parse_json_from_str!(input_str)?;
+
+        let system = fetch_system_from_json!(job_json)?;
+        let system_id = *system.key();
+        drop(system);
+
+        let handle_ids: Vec<u64> = COMPLETED
+            .get_mut(&system_id)
+            .map(|mut ids| std::mem::take(&mut *ids).into_values().collect())
+            .unwrap_or_default();
+        for handle_id in &handle_ids {
+            LISTED.remove(handle_id);
+        }
+
+        Ok(handle_ids)
+    }
+
+    /// Stats of every system created through create_jobsystem, ordered by system id
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn all_system_stats() -> Vec<(u64, SystemStats)> {
+        let mut stats: Vec<_> = SYSTEM_MAP
+            .iter()
+            .map(|system| (*system.key(), system.lock().unwrap().stats()))
+            .collect();
+        stats.sort_unstable_by_key(|(id, _)| *id);
+        stats
+    }
+
+    /// Number of handles held in JOB_MAP per status, including statuses no handle is in
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn handle_status_counts() -> Vec<(&'static str, usize)> {
+        let statuses = [
+            Status::Pending,
+            Status::Queued,
+            Status::Running,
+            Status::Completed,
+            Status::Cancelled,
+            Status::Panicked,
+            Status::TimedOut,
+            Status::ReplayFailed,
+        ];
+        let held: Vec<Status> = JOB_MAP.iter().map(|e| e.get_status()).collect();
+        statuses
+            .into_iter()
+            .map(|status| {
+                let count = held.iter().filter(|&held| *held == status).count();
+                (status_str(status), count)
+            })
+            .collect()
+    }
+
+    /// Names of the job types registered in JOB_KV, sorted
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn job_type_names() -> Vec<String> {
+        let mut names: Vec<String> = JOB_KV.iter().map(|t| t.key().clone()).collect();
+        names.sort_unstable();
+        names
     }
 
     #[no_mangle]
@@ -299,4 +2053,255 @@ pub mod ffi {
             drop(CString::from_raw(ptr));
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use std::fs;
+
+        use super::*;
+        use crate::system::test_util::{call, temp_path};
+
+        #[test]
+        fn journal_replays_every_job_even_past_capacity() {
+            let path = temp_path("replay.journal");
+            // Written the way a process that crashed with jobs in flight leaves its journal
+            let (journal, _) = Journal::open(&path).unwrap();
+            let base = 1 << 40;
+            let request = json!({"type" : "print_success", "input" : {}});
+            for id in base..base + 4 {
+                journal.submitted(id, &request);
+            }
+            journal.finished(base + 3, &Ok(json!({"restored" : true})));
+            journal.submitted(base + 4, &json!({"type" : "no_such_job", "input" : {}}));
+            drop(journal);
+
+            let system = call(
+                create_jobsystem_with_config,
+                json!({"workers" : 1, "capacity" : 1, "journal" : path}),
+            );
+            assert_eq!(system["success"], true);
+            let system_id = &system["system_id"];
+
+            // Jobs that are already resolved are reported right away
+            let completed = call(poll_completed, json!({"system_id" : system_id}));
+            let completed = completed["handle_ids"].as_array().unwrap();
+            assert!(completed.contains(&json!(base + 3)));
+            assert!(completed.contains(&json!(base + 4)));
+
+            for id in base..base + 3 {
+                let result = call(get_job, json!({"handle_id" : id, "timeout_ms" : 5000}));
+                assert_eq!(result["success"], true, "{}", result);
+            }
+            let restored = call(get_job, json!({"handle_id" : base + 3}));
+            assert_eq!(restored["handle_id"], json!({"restored" : true}));
+            let status = call(get_job_status, json!({"handle_id" : base + 4}));
+            assert_eq!(status["status"], "replay_failed");
+            let failed = call(get_job, json!({"handle_id" : base + 4}));
+            assert_eq!(failed["success"], false);
+            assert!(failed["error"].as_str().unwrap().contains("no_such_job"));
+
+            // Every result was handed out, so there is nothing left to replay
+            call(destroy_jobsystem, json!({"system_id" : system_id}));
+            let (_, remaining) = Journal::open(&path).unwrap();
+            assert!(remaining.is_empty());
+            fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn sweep_expires_uncollected_results_and_later_forgets_them() {
+            let system = call(
+                create_jobsystem_with_config,
+                json!({"workers" : 1, "result_ttl_ms" : 60_000}),
+            );
+            let system_id = &system["system_id"];
+            let send = || {
+                let job = json!({"system_id" : system_id, "type" : "print_success", "input" : {}});
+                call(send_job, job)["handle_id"].clone()
+            };
+            let (collected, uncollected) = (send(), send());
+            let status = |handle_id: &Value| call(get_job_status, json!({"handle_id" : handle_id}));
+            for _ in 0..500 {
+                if status(&uncollected)["status"] == "completed" {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            let start = Instant::now();
+            let result = call(
+                get_job,
+                json!({"handle_id" : collected, "timeout_ms" : 5000}),
+            );
+            assert_eq!(result["success"], true);
+
+            sweep(start + Duration::from_secs(30));
+            assert_eq!(status(&uncollected)["status"], "completed");
+
+            let expired_at = start + Duration::from_secs(61);
+            sweep(expired_at);
+            assert_eq!(status(&uncollected)["status"], "expired");
+            let expired = call(get_job, json!({"handle_id" : uncollected}));
+            assert_eq!(expired["error"], "the result of the job has expired");
+            // A result collected before the sweep is gone for good, rather than expired
+            assert_eq!(status(&collected)["success"], false);
+
+            sweep(expired_at + EXPIRED_RETENTION + Duration::from_secs(1));
+            let forgotten = status(&uncollected);
+            assert_eq!(forgotten["error"], "specified handle id was not found");
+
+            call(destroy_jobsystem, json!({"system_id" : system_id}));
+        }
+
+        #[test]
+        fn poll_completed_leaves_out_collected_and_released_jobs() {
+            let system = call(create_jobsystem_with_config, json!({"workers" : 1}));
+            let system_id = &system["system_id"];
+            let send = || {
+                let job = json!({"system_id" : system_id, "type" : "print_success", "input" : {}});
+                call(send_job, job)["handle_id"].clone()
+            };
+            let (collected, released, uncollected) = (send(), send(), send());
+            for handle_id in [&collected, &released, &uncollected] {
+                for _ in 0..500 {
+                    let status = call(get_job_status, json!({"handle_id" : handle_id}));
+                    if status["status"] == "completed" {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+            let result = call(get_job, json!({"handle_id" : collected}));
+            assert_eq!(result["success"], true);
+            let result = call(release_job, json!({"handle_id" : released}));
+            assert_eq!(result["success"], true);
+
+            let completed = call(poll_completed, json!({"system_id" : system_id}));
+            assert_eq!(completed["handle_ids"], json!([uncollected]));
+            let completed = call(poll_completed, json!({"system_id" : system_id}));
+            assert_eq!(completed["handle_ids"], json!([]));
+
+            call(destroy_jobsystem, json!({"system_id" : system_id}));
+        }
+
+        #[test]
+        fn handles_stay_reachable_while_get_job_waits() {
+            // Without workers the job stays queued until it is cancelled
+            let system = call(create_jobsystem_with_config, json!({"workers" : 0}));
+            let system_id = &system["system_id"];
+            let job = json!({"system_id" : system_id, "type" : "print_success", "input" : {}});
+            let handle_id = call(send_job, job)["handle_id"].clone();
+
+            let waiter = {
+                let handle_id = handle_id.clone();
+                thread::spawn(move || {
+                    call(
+                        get_job,
+                        json!({"handle_id" : handle_id, "timeout_ms" : 5000}),
+                    )
+                })
+            };
+            thread::sleep(Duration::from_millis(50));
+            let status = call(get_job_status, json!({"handle_id" : handle_id}));
+            assert_eq!(status["status"], "queued");
+            let cancelled = call(cancel_job, json!({"handle_id" : handle_id}));
+            assert_eq!(cancelled["success"], true, "{}", cancelled);
+
+            let result = waiter.join().unwrap();
+            assert_eq!(result["error"], "job was cancelled");
+            let status = call(get_job_status, json!({"handle_id" : handle_id}));
+            assert_eq!(status["error"], "specified handle id was not found");
+
+            call(destroy_jobsystem, json!({"system_id" : system_id}));
+        }
+
+        #[test]
+        fn named_queues_keep_a_worker_and_reject_jobs_nobody_serves() {
+            let system = call(
+                create_jobsystem_with_config,
+                json!({"workers" : 1, "queues" : {"io" : 1}}),
+            );
+            let system_id = &system["system_id"];
+            let send = |queue: &str| {
+                let job = json!({"system_id" : system_id, "type" : "print_success", "input" : {}, "queue" : queue});
+                call(send_job, job)
+            };
+            let unserved = send("llm");
+            assert_eq!(unserved["error"], "queue 'llm' is not served by any worker");
+            let batch = json!({"system_id" : system_id, "type" : "print_success", "inputs" : [{}], "queue" : "llm"});
+            assert_eq!(call(send_jobs, batch)["success"], false);
+
+            // Only the default queue may be left without a worker
+            let remove =
+                || call(remove_worker, json!({"system_id" : system_id}))["success"].clone();
+            assert_eq!(remove(), true);
+            assert_eq!(remove(), false);
+            let resized = call(
+                set_worker_count,
+                json!({"system_id" : system_id, "count" : 0}),
+            );
+            assert_eq!(resized["workers"], 1);
+            let handle_id = send("io")["handle_id"].clone();
+            let result = call(
+                get_job,
+                json!({"handle_id" : handle_id, "timeout_ms" : 5000}),
+            );
+            assert_eq!(result["success"], true, "{}", result);
+
+            call(destroy_jobsystem, json!({"system_id" : system_id}));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::system::job_context;
+
+    #[test]
+    fn draining_runs_every_queued_job() {
+        let mut system = JobSystemBuilder::new().workers(1).build();
+        let handles = system.map(0..10, |x: u32| {
+            thread::sleep(Duration::from_millis(2));
+            x
+        });
+        assert!(system.shutdown(ShutdownMode::Drain, None));
+        let results: Vec<_> = handles.into_iter().map(JobHandle::get).collect();
+        assert_eq!(results, (0..10).map(Ok).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn aborting_cancels_queued_jobs_and_signals_running_ones() {
+        let mut system = JobSystemBuilder::new().workers(1).build();
+        let (started, running) = mpsc::channel();
+        let looping = system.send_job(1, move |x: u32| {
+            started.send(()).unwrap();
+            while !job_context::is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            x
+        });
+        let queued = system.send_job(2, |x| x);
+        running.recv().unwrap();
+
+        assert!(system.shutdown(ShutdownMode::Abort, None));
+        assert_eq!(looping.get(), Err(JobError::Cancelled));
+        assert_eq!(queued.get(), Err(JobError::Cancelled));
+    }
+
+    #[test]
+    fn jobs_still_running_at_the_deadline_are_left_behind() {
+        let mut system = JobSystemBuilder::new().workers(1).build();
+        let (started, running) = mpsc::channel();
+        let stuck = system.send_job(1, move |x: u32| {
+            started.send(()).unwrap();
+            thread::sleep(Duration::from_millis(300));
+            x
+        });
+        running.recv().unwrap();
+
+        assert!(!system.shutdown(ShutdownMode::Drain, Some(Duration::from_millis(30))));
+        assert_eq!(stuck.get_status(), Status::Cancelled);
+        assert_eq!(stuck.get(), Err(JobError::Cancelled));
+    }
 }
@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::job_handle::JobError;
+
+/// The journal is compacted once it holds this many records and twice as many as right after it was last compacted
+const COMPACT_MIN_RECORDS: usize = 4096;
+
+/// One line of the journal
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    /// Written before the handle id of a job is handed out, holding the send_job request that created it
+    Submitted { id: u64, request: Value },
+    Finished {
+        id: u64,
+        result: Result<Value, JobError>,
+    },
+    /// The result was handed out, so the job no longer needs to be kept
+    Released { id: u64 },
+}
+
+/// A job read back from the journal
+#[derive(Debug)]
+pub(crate) struct JournaledJob {
+    pub(crate) id: u64,
+    pub(crate) request: Value,
+    /// None if the job never finished, in which case it has to run again
+    pub(crate) result: Option<Result<Value, JobError>>,
+}
+
+/// The file a journal appends to, along with how many records it holds
+#[derive(Debug)]
+struct JournalFile {
+    file: File,
+    records: usize,
+    /// Records the file held right after it was last compacted
+    compacted: usize,
+}
+
+/// Write-ahead log of the jobs sent to a system through the FFI, as lines of JSON. Every record is written out before
+/// the call that caused it returns, so the journal survives the process crashing. The file is compacted when it is
+/// opened and whenever it has doubled in size since, so it only grows with the jobs that were not released
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: Mutex<JournalFile>,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if it does not exist, and returns the jobs in it that were not released,
+    /// in the order they were sent. The file is compacted to hold only those jobs
+    pub(crate) fn open(path: &Path) -> io::Result<(Self, Vec<JournaledJob>)> {
+        let jobs = match File::open(path) {
+            Ok(file) => Self::read(file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let file = Self::compact(path, &jobs)?;
+        Ok((
+            Self {
+                path: path.into(),
+                file: Mutex::new(file),
+            },
+            jobs,
+        ))
+    }
+
+    /// Rewrites the journal at `path` to hold only `jobs`, returning the file to append to
+    fn compact(path: &Path, jobs: &[JournaledJob]) -> io::Result<JournalFile> {
+        // Written aside and renamed over the journal, so a crash halfway through leaves the old journal intact
+        let mut compacted_path = path.as_os_str().to_owned();
+        compacted_path.push(".tmp");
+        let mut compacted = File::create(&compacted_path)?;
+        let mut records = 0;
+        for job in jobs {
+            Self::write(
+                &mut compacted,
+                &Record::Submitted {
+                    id: job.id,
+                    request: job.request.clone(),
+                },
+            )?;
+            records += 1;
+            if let Some(result) = &job.result {
+                let result = result.clone();
+                Self::write(&mut compacted, &Record::Finished { id: job.id, result })?;
+                records += 1;
+            }
+        }
+        compacted.sync_all()?;
+        fs::rename(&compacted_path, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(JournalFile {
+            file,
+            records,
+            compacted: records,
+        })
+    }
+
+    fn read(file: File) -> io::Result<Vec<JournaledJob>> {
+        let mut jobs: Vec<Option<JournaledJob>> = Vec::new();
+        let mut index = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            // A crash may cut the last record short, which only loses that record
+            let Ok(record) = serde_json::from_str::<Record>(&line) else {
+                tracing::warn!("Skipping unreadable journal record: {}", line);
+                continue;
+            };
+            match record {
+                Record::Submitted { id, request } => {
+                    index.insert(id, jobs.len());
+                    jobs.push(Some(JournaledJob {
+                        id,
+                        request,
+                        result: None,
+                    }));
+                }
+                Record::Finished { id, result } => {
+                    if let Some(job) = index.get(&id).and_then(|&i| jobs[i].as_mut()) {
+                        job.result = Some(result);
+                    }
+                }
+                Record::Released { id } => {
+                    if let Some(i) = index.remove(&id) {
+                        jobs[i] = None;
+                    }
+                }
+            }
+        }
+        Ok(jobs.into_iter().flatten().collect())
+    }
+
+    pub(crate) fn submitted(&self, id: u64, request: &Value) {
+        let request = request.clone();
+        self.append(&Record::Submitted { id, request });
+    }
+
+    pub(crate) fn finished(&self, id: u64, result: &Result<Value, JobError>) {
+        let result = result.clone();
+        self.append(&Record::Finished { id, result });
+    }
+
+    pub(crate) fn released(&self, id: u64) {
+        self.append(&Record::Released { id });
+    }
+
+    /// Failing to journal a job does not fail the job itself, it only leaves the job out of the next replay
+    fn append(&self, record: &Record) {
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = Self::write(&mut file.file, record) {
+            tracing::error!("Failed to write journal record: {}", e);
+            return;
+        }
+        file.records += 1;
+        if file.records < COMPACT_MIN_RECORDS.max(2 * file.compacted) {
+            return;
+        }
+        // The lock is held throughout, so no record is appended to the file that is being replaced
+        let compacted = File::open(&self.path)
+            .and_then(Self::read)
+            .and_then(|jobs| Self::compact(&self.path, &jobs));
+        match compacted {
+            Ok(compacted) => *file = compacted,
+            Err(e) => {
+                tracing::error!("Failed to compact the journal: {}", e);
+                // Tried again once the journal has doubled once more
+                file.compacted = file.records;
+            }
+        }
+    }
+
+    /// Writes the record as a single line in one call, so a crash cuts off at most the last line
+    fn write(file: &mut File, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::system::test_util::temp_path;
+
+    fn lines(path: &Path) -> usize {
+        BufReader::new(File::open(path).unwrap()).lines().count()
+    }
+
+    #[test]
+    fn released_jobs_are_compacted_away_while_the_journal_is_open() {
+        let path = temp_path("compact.journal");
+        let (journal, _) = Journal::open(&path).unwrap();
+        journal.submitted(0, &json!({"type" : "print_success"}));
+        for id in 1..COMPACT_MIN_RECORDS as u64 {
+            journal.submitted(id, &json!({"type" : "print_success"}));
+            journal.finished(id, &Ok(json!({})));
+            journal.released(id);
+        }
+        assert!(lines(&path) < COMPACT_MIN_RECORDS);
+
+        // Appending keeps working on the compacted file
+        journal.finished(0, &Ok(json!({"kept" : true})));
+        drop(journal);
+        let (_, jobs) = Journal::open(&path).unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].result, Some(Ok(json!({"kept" : true}))));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,40 +1,428 @@
 use std::{
-    collections::VecDeque,
-    sync::{Arc, Condvar, Mutex},
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::{self, Debug},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
 };
 
+use super::{
+    job_options::Priority,
+    scheduler::{JobQueue, DEFAULT_QUEUE},
+};
+
+/// Every interval an element spends waiting raises its effective priority by one, so low priority work is not starved
+const AGING_INTERVAL: Duration = Duration::from_millis(500);
+
+struct Entry<T> {
+    value: T,
+    enqueued: Instant,
+}
+
+impl<T> Entry<T> {
+    fn effective_priority(&self, priority: Priority, now: Instant) -> Priority {
+        let boost = now.duration_since(self.enqueued).as_millis() / AGING_INTERVAL.as_millis();
+        priority.saturating_add(boost.try_into().unwrap_or(Priority::MAX))
+    }
+}
+
+/// One FIFO lane per priority. Lanes are dropped once they run empty
+struct PriorityLanes<T> {
+    lanes: BTreeMap<Priority, VecDeque<Entry<T>>>,
+    len: usize,
+}
+
+impl<T> PriorityLanes<T> {
+    fn new() -> Self {
+        Self {
+            lanes: BTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, value: T, priority: Priority) {
+        self.lanes.entry(priority).or_default().push_back(Entry {
+            value,
+            enqueued: Instant::now(),
+        });
+        self.len += 1;
+    }
+
+    /// Finds the element to serve next, returning its lane, its effective priority and when it was enqueued.
+    /// Compares the heads of every lane, since aging may have lifted an older, lower priority element above the rest
+    fn next(&self, now: Instant) -> Option<(Priority, Priority, Instant)> {
+        self.lanes
+            .iter()
+            .filter_map(|(&priority, lane)| {
+                lane.front().map(|head| {
+                    (
+                        priority,
+                        head.effective_priority(priority, now),
+                        head.enqueued,
+                    )
+                })
+            })
+            .max_by_key(|&(_, effective, enqueued)| (effective, Reverse(enqueued)))
+    }
+
+    /// Takes the first element matching `predicate` out of the lanes, if there is one
+    fn remove_where(&mut self, predicate: &dyn Fn(&T) -> bool) -> Option<T> {
+        let (priority, index) = self.lanes.iter().find_map(|(&priority, lane)| {
+            lane.iter()
+                .position(|e| predicate(&e.value))
+                .map(|index| (priority, index))
+        })?;
+        self.take(priority, index)
+    }
+
+    fn take(&mut self, priority: Priority, index: usize) -> Option<T> {
+        let lane = self.lanes.get_mut(&priority)?;
+        let value = lane.remove(index).map(|e| e.value);
+        if lane.is_empty() {
+            self.lanes.remove(&priority);
+        }
+        if value.is_some() {
+            self.len -= 1;
+        }
+        value
+    }
+}
+
+/// The named queues of a `JobQueue`, each holding its own priority lanes
+pub(crate) struct QueueSet<T> {
+    queues: HashMap<String, PriorityLanes<T>>,
+    len: usize,
+}
+
+impl<T> QueueSet<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            queues: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn push(&mut self, value: T, queue: &str, priority: Priority) {
+        match self.queues.get_mut(queue) {
+            Some(lanes) => lanes.push(value, priority),
+            None => {
+                let mut lanes = PriorityLanes::new();
+                lanes.push(value, priority);
+                self.queues.insert(queue.into(), lanes);
+            }
+        }
+        self.len += 1;
+    }
+
+    /// Pops the element with the highest effective priority across the `subscribed` queues, the oldest one first
+    /// among equals
+    pub(crate) fn pop(&mut self, subscribed: &[String]) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let (queue, lane) = subscribed
+            .iter()
+            .filter_map(|queue| {
+                let next = self.queues.get(queue)?.next(now)?;
+                Some((queue, next))
+            })
+            .max_by_key(|&(_, (_, effective, enqueued))| (effective, Reverse(enqueued)))
+            .map(|(queue, (lane, _, _))| (queue, lane))?;
+        let value = self.queues.get_mut(queue)?.take(lane, 0);
+        if value.is_some() {
+            self.len -= 1;
+        }
+        value
+    }
+
+    /// Whether any of the `subscribed` queues holds an element
+    pub(crate) fn has_work(&self, subscribed: &[String]) -> bool {
+        self.len > 0
+            && subscribed
+                .iter()
+                .any(|queue| self.queues.get(queue).is_some_and(|lanes| lanes.len > 0))
+    }
+
+    /// Takes the first element matching `predicate` out of any queue, if there is one
+    pub(crate) fn remove_where(&mut self, predicate: &dyn Fn(&T) -> bool) -> Option<T> {
+        let value = self
+            .queues
+            .values_mut()
+            .find_map(|lanes| lanes.remove_where(predicate))?;
+        self.len -= 1;
+        Some(value)
+    }
+}
+
+/// Puts the queues a worker subscribes to in a fixed order, so two workers with the same subscriptions compare equal.
+/// A worker that names no queue serves the default one
+fn normalize_subscriptions(queues: &[String]) -> Vec<String> {
+    let mut queues = queues.to_vec();
+    if queues.is_empty() {
+        queues.push(DEFAULT_QUEUE.into());
+    }
+    queues.sort_unstable();
+    queues.dedup();
+    queues
+}
+
+/// Where a worker slot of a `Roster` stands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Slot {
+    Serving,
+    /// The worker was asked to exit once it runs out of work
+    Leaving,
+    /// The worker has exited, so the next worker to register takes the slot over
+    Free,
+}
+
+/// The queues each worker serves, by the index `register` handed out, and which workers were asked to exit. Slots of
+/// workers that exited are handed out again, so a pool that is resized over and over does not grow its bookkeeping
 #[derive(Debug)]
+pub(crate) struct Roster {
+    subscriptions: Vec<Vec<String>>,
+    slots: Vec<Slot>,
+    /// Whether every worker serves the same queues, in which case any worker can be woken for any element
+    uniform: bool,
+}
+
+impl Roster {
+    pub(crate) fn new() -> Self {
+        Self {
+            subscriptions: Vec::new(),
+            slots: Vec::new(),
+            uniform: true,
+        }
+    }
+
+    /// Takes the first free slot, or a new one if there is none
+    pub(crate) fn register(&mut self, queues: &[String]) -> usize {
+        let queues = normalize_subscriptions(queues);
+        self.uniform = (0..self.len())
+            .filter(|&worker| self.slots[worker] != Slot::Free)
+            .all(|worker| self.subscriptions[worker] == queues);
+        match self.slots.iter().position(|&slot| slot == Slot::Free) {
+            Some(worker) => {
+                self.subscriptions[worker] = queues;
+                self.slots[worker] = Slot::Serving;
+                worker
+            }
+            None => {
+                self.subscriptions.push(queues);
+                self.slots.push(Slot::Serving);
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    /// Number of slots, including free ones
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub(crate) fn uniform(&self) -> bool {
+        self.uniform
+    }
+
+    pub(crate) fn subscriptions(&self, worker: usize) -> &[String] {
+        &self.subscriptions[worker]
+    }
+
+    pub(crate) fn is_leaving(&self, worker: usize) -> bool {
+        self.slots[worker] != Slot::Serving
+    }
+
+    /// Frees the slot of a worker that was asked to exit and is about to
+    pub(crate) fn exited(&mut self, worker: usize) {
+        self.slots[worker] = Slot::Free;
+    }
+
+    /// Whether `worker` subscribes to `queue` and was not asked to exit
+    pub(crate) fn serves_as(&self, worker: usize, queue: &str) -> bool {
+        self.slots[worker] == Slot::Serving && self.subscriptions[worker].iter().any(|q| q == queue)
+    }
+
+    /// Whether any worker that was not asked to exit serves `queue`
+    pub(crate) fn serves(&self, queue: &str) -> bool {
+        (0..self.len()).any(|worker| self.serves_as(worker, queue))
+    }
+
+    /// Asks the most recently added worker that can leave to exit. A worker can leave as long as every named queue it
+    /// serves keeps another worker, while the default queue may be left without any, as jobs sent to it wait for the
+    /// next worker to be added. Returns false if no worker can leave
+    pub(crate) fn retire_one(&mut self) -> bool {
+        let Some(worker) = (0..self.len()).rev().find(|&worker| {
+            self.slots[worker] == Slot::Serving
+                && self.subscriptions[worker].iter().all(|queue| {
+                    queue == DEFAULT_QUEUE
+                        || (0..self.len())
+                            .any(|other| other != worker && self.serves_as(other, queue))
+                })
+        }) else {
+            return false;
+        };
+        self.slots[worker] = Slot::Leaving;
+        true
+    }
+
+    /// Asks every worker to exit
+    pub(crate) fn retire_all(&mut self) {
+        for slot in &mut self.slots {
+            if *slot == Slot::Serving {
+                *slot = Slot::Leaving;
+            }
+        }
+    }
+}
+
+struct State<T> {
+    queues: QueueSet<T>,
+    roster: Roster,
+}
+
+/// A single queue shared by every worker
 pub(crate) struct MessageQueue<T>
 where
     T: Send + Sync,
 {
-    queue: Mutex<VecDeque<T>>,
+    state: Mutex<State<T>>,
     available: Condvar,
 }
 
 impl<T: Send + Sync> MessageQueue<T> {
-    pub(crate) fn new() -> Arc<Self> {
-        Arc::new(Self {
-            queue: Mutex::new(VecDeque::new()),
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                queues: QueueSet::new(),
+                roster: Roster::new(),
+            }),
             available: Condvar::new(),
-        })
+        }
+    }
+
+    /// Wakes a worker that can take an element. While workers serve different queues, the one woken might not serve
+    /// the queue the element went to, so all of them are woken instead
+    fn wake(&self, state: &State<T>) {
+        if state.roster.uniform() {
+            self.available.notify_one();
+        } else {
+            self.available.notify_all();
+        }
+    }
+}
+
+impl<T: Send + Sync> JobQueue<T> for MessageQueue<T> {
+    fn register(&self, queues: &[String]) -> usize {
+        self.state.lock().unwrap().roster.register(queues)
+    }
+
+    fn serves(&self, queue: &str) -> bool {
+        self.state.lock().unwrap().roster.serves(queue)
+    }
+
+    fn send(&self, value: T, queue: &str, priority: Priority) {
+        let mut state = self.state.lock().unwrap();
+        state.queues.push(value, queue, priority);
+        self.wake(&state);
     }
-    pub(crate) fn send(&self, value: T) {
-        let mut queue = self.queue.lock().unwrap();
-        queue.push_back(value);
-        self.available.notify_one();
+
+    fn send_batch(&self, values: Vec<T>, queue: &str, priority: Priority) {
+        let mut state = self.state.lock().unwrap();
+        for value in values {
+            state.queues.push(value, queue, priority);
+        }
+        self.available.notify_all();
     }
 
-    /// Receives an element from queue. If multiple threads are waiting on recv(), the thread chosen is nondeterministic
-    pub(crate) fn recv(&self) -> T {
-        let mut queue = self.queue.lock().unwrap();
+    fn remove_where(&self, predicate: &dyn Fn(&T) -> bool) -> Option<T> {
+        self.state.lock().unwrap().queues.remove_where(predicate)
+    }
+
+    /// Receives the element with the highest effective priority among the queues `worker` serves. If multiple threads are waiting on recv(), the thread chosen is nondeterministic
+    fn recv(&self, worker: usize) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
         // The purpose of the loop is to handle cases of unlocks where `available` was notified spuriously
         loop {
-            if let Some(value) = queue.pop_front() {
-                return value;
+            let State { queues, roster } = &mut *state;
+            if let Some(value) = queues.pop(roster.subscriptions(worker)) {
+                return Some(value);
+            } else if roster.is_leaving(worker) {
+                roster.exited(worker);
+                return None;
             } else {
-                queue = self.available.wait(queue).unwrap();
+                state = self.available.wait(state).unwrap();
             }
         }
     }
+
+    /// Every worker is woken, as the one asked to exit may be any of them
+    fn join_one(&self) -> bool {
+        let retired = self.state.lock().unwrap().roster.retire_one();
+        if retired {
+            self.available.notify_all();
+        }
+        retired
+    }
+
+    fn join_all(&self) {
+        self.state.lock().unwrap().roster.retire_all();
+        self.available.notify_all();
+    }
+}
+
+impl<T: Send + Sync> Debug for MessageQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("MessageQueue")
+            .field("queued", &state.queues.len())
+            .field("roster", &state.roster)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_of_workers_that_exited_are_handed_out_again() {
+        let queue = MessageQueue::<u32>::new();
+        let queues = [DEFAULT_QUEUE.to_string()];
+        let first = queue.register(&queues);
+        let second = queue.register(&queues);
+        assert!(queue.join_one());
+        // The most recently added worker leaves, once it finds no work
+        assert_eq!(queue.recv(second), None);
+        assert_eq!(queue.register(&queues), second);
+        assert_eq!(queue.state.lock().unwrap().roster.len(), 2);
+
+        queue.send(7, DEFAULT_QUEUE, 0);
+        assert_eq!(queue.recv(first), Some(7));
+    }
+
+    #[test]
+    fn waiting_elements_age_past_higher_priorities() {
+        let mut set = QueueSet::new();
+        let subscribed = [DEFAULT_QUEUE.to_string()];
+        set.push("low", DEFAULT_QUEUE, 0);
+        set.push("high", DEFAULT_QUEUE, 1);
+        assert_eq!(set.pop(&subscribed), Some("high"));
+        assert_eq!(set.pop(&subscribed), Some("low"));
+
+        set.push("old", DEFAULT_QUEUE, 0);
+        set.push("urgent", DEFAULT_QUEUE, 2);
+        set.push("later", DEFAULT_QUEUE, 2);
+        // As if it had waited three aging intervals, lifting it from 0 to 3
+        let lanes = set.queues.get_mut(DEFAULT_QUEUE).unwrap();
+        lanes.lanes.get_mut(&0).unwrap()[0].enqueued -= AGING_INTERVAL * 3;
+        assert_eq!(set.pop(&subscribed), Some("old"));
+        assert_eq!(set.pop(&subscribed), Some("urgent"));
+        assert_eq!(set.pop(&subscribed), Some("later"));
+        assert_eq!(set.pop(&subscribed), None);
+    }
 }
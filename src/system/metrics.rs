@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::job_handle::JobError;
+
+/// Upper bounds of the histogram buckets in seconds. Durations above the last bound land in a final overflow bucket
+pub const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+/// Job type that jobs sent without one are counted under
+pub const UNTYPED: &str = "untyped";
+
+/// Distribution of a duration, bucketed by `BUCKETS`
+#[derive(Clone, Debug, Serialize)]
+pub struct Histogram {
+    pub count: u64,
+    /// Sum of all recorded durations in seconds
+    pub sum: f64,
+    /// Number of durations per bucket, not cumulative. Holds one more entry than `BUCKETS` for the overflow bucket
+    pub buckets: Vec<u64>,
+}
+
+impl Histogram {
+    fn record(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_secs_f64(self.sum / self.count as f64))
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum: 0.0,
+            buckets: vec![0; BUCKETS.len() + 1],
+        }
+    }
+}
+
+/// Outcomes and run times of the jobs of one type
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct JobTypeStats {
+    pub completed: u64,
+    /// Jobs that panicked or timed out
+    pub failed: u64,
+    /// Jobs that were cancelled or whose dependencies failed
+    pub cancelled: u64,
+    /// Time spent running, with every attempt of a retried job recorded on its own
+    pub run_time: Histogram,
+}
+
+/// A snapshot of the metrics of a JobSystem, as returned by `JobSystem::stats`
+#[derive(Clone, Debug, Serialize)]
+pub struct SystemStats {
+    pub workers: usize,
+    /// Jobs waiting in the queue right now
+    pub queued: u64,
+    /// Jobs being run right now
+    pub running: u64,
+    pub completed: u64,
+    pub failed: u64,
+    pub cancelled: u64,
+    /// Time jobs spent in the queue before a worker started them
+    pub queue_wait: Histogram,
+    pub job_types: HashMap<String, JobTypeStats>,
+    /// Share of the time workers have been alive that they spent running jobs, between 0 and 1
+    pub utilization: f64,
+}
+
+#[derive(Debug, Default)]
+struct Timings {
+    queue_wait: Histogram,
+    job_types: HashMap<String, JobTypeStats>,
+    busy: Duration,
+}
+
+#[derive(Debug)]
+struct Capacity {
+    workers: usize,
+    since: Instant,
+    /// Worker time accumulated before `since`
+    worker_time: Duration,
+}
+
+/// Collects the metrics of one JobSystem. Every job reports its own transitions through its HandleInner
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    /// Also bounds the queue of systems with a capacity, which wait on `room` for it to drop
+    queued: Mutex<u64>,
+    room: Condvar,
+    running: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    cancelled: AtomicU64,
+    timings: Mutex<Timings>,
+    capacity: Mutex<Capacity>,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Self {
+            queued: Mutex::new(0),
+            room: Condvar::new(),
+            running: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            cancelled: AtomicU64::new(0),
+            timings: Mutex::new(Timings::default()),
+            capacity: Mutex::new(Capacity {
+                workers: 0,
+                since: Instant::now(),
+                worker_time: Duration::ZERO,
+            }),
+        }
+    }
+
+    pub(crate) fn enqueued(&self) {
+        *self.queued.lock().unwrap() += 1;
+    }
+
+    pub(crate) fn dequeued(&self) {
+        *self.queued.lock().unwrap() -= 1;
+        self.room.notify_all();
+    }
+
+    /// Blocks until at least `needed` more jobs fit in a queue of `capacity`, or until `until` has passed.
+    /// Returns how many jobs fit, or None if there was not enough room in time
+    pub(crate) fn wait_for_room(
+        &self,
+        capacity: usize,
+        needed: usize,
+        until: Option<Instant>,
+    ) -> Option<usize> {
+        let mut queued = self.queued.lock().unwrap();
+        loop {
+            let room = capacity.saturating_sub(*queued as usize);
+            if room >= needed {
+                return Some(room);
+            }
+            queued = match until {
+                None => self.room.wait(queued).unwrap(),
+                Some(until) => {
+                    let now = Instant::now();
+                    if now >= until {
+                        return None;
+                    }
+                    self.room.wait_timeout(queued, until - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    pub(crate) fn started(&self, wait: Duration) {
+        self.dequeued();
+        self.running.fetch_add(1, Ordering::Relaxed);
+        self.timings.lock().unwrap().queue_wait.record(wait);
+    }
+
+    /// Records one attempt at running a job, whether or not it is retried afterwards
+    pub(crate) fn stopped(&self, job_type: &str, run_time: Duration) {
+        self.running.fetch_sub(1, Ordering::Relaxed);
+        let mut timings = self.timings.lock().unwrap();
+        timings.busy += run_time;
+        timings
+            .job_types
+            .entry(job_type.into())
+            .or_default()
+            .run_time
+            .record(run_time);
+    }
+
+    pub(crate) fn finished<Y>(&self, job_type: &str, result: &Result<Y, JobError>) {
+        let mut timings = self.timings.lock().unwrap();
+        let stats = timings.job_types.entry(job_type.into()).or_default();
+        let (total, count) = match result {
+            Ok(_) => (&self.completed, &mut stats.completed),
+            Err(JobError::Panicked(_) | JobError::TimedOut | JobError::ReplayFailed(_)) => {
+                (&self.failed, &mut stats.failed)
+            }
+            Err(JobError::Cancelled | JobError::DependencyFailed) => {
+                (&self.cancelled, &mut stats.cancelled)
+            }
+        };
+        total.fetch_add(1, Ordering::Relaxed);
+        *count += 1;
+    }
+
+    /// Called whenever the number of workers changes, so utilization accounts for the time each count was in effect
+    pub(crate) fn set_workers(&self, workers: usize) {
+        let mut capacity = self.capacity.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = (now - capacity.since) * capacity.workers as u32;
+        capacity.worker_time += elapsed;
+        capacity.since = now;
+        capacity.workers = workers;
+    }
+
+    pub(crate) fn snapshot(&self, workers: usize) -> SystemStats {
+        let worker_time = {
+            let capacity = self.capacity.lock().unwrap();
+            capacity.worker_time + (Instant::now() - capacity.since) * capacity.workers as u32
+        };
+        let timings = self.timings.lock().unwrap();
+        let utilization = if worker_time.is_zero() {
+            0.0
+        } else {
+            (timings.busy.as_secs_f64() / worker_time.as_secs_f64()).min(1.0)
+        };
+        SystemStats {
+            workers,
+            queued: *self.queued.lock().unwrap(),
+            running: self.running.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            cancelled: self.cancelled.load(Ordering::Relaxed),
+            queue_wait: timings.queue_wait.clone(),
+            job_types: timings.job_types.clone(),
+            utilization,
+        }
+    }
+}
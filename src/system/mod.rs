@@ -1,4 +1,17 @@
-mod job_handle;
-pub(crate) mod job_system;
+pub mod job_context;
+pub mod job_handle;
+pub mod job_options;
+pub mod job_system;
+mod journal;
 mod message_queue;
+pub mod metrics;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod result_cache;
+pub mod scheduler;
+#[cfg(test)]
+mod test_util;
+mod timer;
+mod type_limits;
+mod work_stealing;
 mod worker;
@@ -0,0 +1,361 @@
+use std::{
+    collections::BTreeSet,
+    ffi::{c_char, CStr, CString},
+    fmt::{Display, Write as _},
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use super::{
+    job_system::ffi,
+    metrics::{Histogram, SystemStats, BUCKETS},
+};
+
+/// Name, help text and value of a gauge reported per system
+type Gauge = (&'static str, &'static str, fn(&SystemStats) -> f64);
+
+/// Renders the metrics of every system created through the FFI, together with the statuses of the handles it holds,
+/// in the Prometheus text exposition format
+pub fn render() -> String {
+    let systems: Vec<(String, SystemStats)> = ffi::all_system_stats()
+        .into_iter()
+        .map(|(id, stats)| (id.to_string(), stats))
+        .collect();
+    let mut out = String::new();
+
+    let gauges: [Gauge; 4] = [
+        ("job_system_workers", "Workers serving jobs", |s| {
+            s.workers as f64
+        }),
+        ("job_system_jobs_queued", "Jobs waiting in the queue", |s| {
+            s.queued as f64
+        }),
+        ("job_system_jobs_running", "Jobs being run", |s| {
+            s.running as f64
+        }),
+        (
+            "job_system_worker_utilization",
+            "Share of worker time spent running jobs",
+            |s| s.utilization,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        header(&mut out, name, "gauge", help);
+        for (system, stats) in &systems {
+            sample(&mut out, name, &[("system", system)], value(stats));
+        }
+    }
+
+    header(
+        &mut out,
+        "job_system_jobs_total",
+        "counter",
+        "Finished jobs by type and outcome",
+    );
+    for (system, stats) in &systems {
+        for job_type in job_types(stats) {
+            let job_stats = stats.job_types.get(&job_type).cloned().unwrap_or_default();
+            for (outcome, count) in [
+                ("completed", job_stats.completed),
+                ("failed", job_stats.failed),
+                ("cancelled", job_stats.cancelled),
+            ] {
+                let labels = [
+                    ("system", system.as_str()),
+                    ("type", &job_type),
+                    ("outcome", outcome),
+                ];
+                sample(&mut out, "job_system_jobs_total", &labels, count);
+            }
+        }
+    }
+
+    header(
+        &mut out,
+        "job_system_queue_wait_seconds",
+        "histogram",
+        "Time jobs spent queued before a worker started them",
+    );
+    for (system, stats) in &systems {
+        histogram(
+            &mut out,
+            "job_system_queue_wait_seconds",
+            &[("system", system)],
+            &stats.queue_wait,
+        );
+    }
+    header(
+        &mut out,
+        "job_system_run_time_seconds",
+        "histogram",
+        "Time spent running each attempt of a job",
+    );
+    for (system, stats) in &systems {
+        for job_type in job_types(stats) {
+            let run_time = stats
+                .job_types
+                .get(&job_type)
+                .map(|job_stats| job_stats.run_time.clone())
+                .unwrap_or_default();
+            let labels = [("system", system.as_str()), ("type", &job_type)];
+            histogram(&mut out, "job_system_run_time_seconds", &labels, &run_time);
+        }
+    }
+
+    header(
+        &mut out,
+        "job_system_handles",
+        "gauge",
+        "Job handles held by the FFI, by status",
+    );
+    for (status, count) in ffi::handle_status_counts() {
+        sample(&mut out, "job_system_handles", &[("status", status)], count);
+    }
+    out
+}
+
+/// The types registered in JOB_KV, which are listed even before any job of theirs ran, and every other type the
+/// system has seen
+fn job_types(stats: &SystemStats) -> BTreeSet<String> {
+    let mut types: BTreeSet<String> = ffi::job_type_names().into_iter().collect();
+    types.extend(stats.job_types.keys().cloned());
+    types
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl Display) {
+    let _ = writeln!(out, "{}{} {}", name, label_set(labels), value);
+}
+
+/// Writes the cumulative buckets, sum and count of a histogram
+fn histogram(out: &mut String, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+    let mut cumulative = 0;
+    let bounds = BUCKETS.iter().map(|b| b.to_string()).chain(["+Inf".into()]);
+    for (bound, count) in bounds.zip(&histogram.buckets) {
+        cumulative += count;
+        let mut bucket_labels = labels.to_vec();
+        bucket_labels.push(("le", bound.as_str()));
+        sample(out, &format!("{}_bucket", name), &bucket_labels, cumulative);
+    }
+    sample(out, &format!("{}_sum", name), labels, histogram.sum);
+    sample(out, &format!("{}_count", name), labels, histogram.count);
+}
+
+fn label_set(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Serves `render` over HTTP on localhost until dropped
+#[derive(Debug)]
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Listens on 127.0.0.1 at `port`, or at a free port if `port` is 0
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    // A client that goes away halfway only loses its own scrape
+                    let _ = respond(stream);
+                }
+            }
+        });
+        Ok(Self {
+            addr,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are read and ignored, so the client sees its whole request consumed
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match path {
+        "/metrics" | "/" => ("200 OK", render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // The listener only checks the flag once a connection comes in, so make one
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            thread
+                .join()
+                .unwrap_or_else(|e| tracing::error!("Failed to join metrics thread: {:?}", e));
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SERVER: Mutex<Option<MetricsServer>> = Mutex::new(None);
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+/// Starts serving metrics on localhost at the optional "port", returning the "port" it listens on.
+/// Replaces the server started by an earlier call
+pub extern "C" fn start_metrics_server(json_str_ptr: *const c_char) -> *const c_char {
+    let output_json = if json_str_ptr.is_null() {
+        json!({"error" : "json_str_ptr was a null pointer"})
+    } else {
+        let input_str = unsafe { CStr::from_ptr(json_str_ptr).to_str().unwrap() };
+
+        match process_and_start_server(input_str) {
+            Ok(port) => json!({"success" : true, "port" : port}),
+            Err(message) => json!({"success" : false, "error" : message}),
+        }
+    };
+    CString::new(output_json.to_string()).unwrap().into_raw()
+}
+
+fn process_and_start_server(input_str: &str) -> Result<u16, String> {
+    let json = Value::from_str(input_str).map_err(|_| "Unable to parse json")?;
+    let port = match &json["port"] {
+        Value::Null => 0,
+        port => port
+            .as_u64()
+            .and_then(|port| u16::try_from(port).ok())
+            .ok_or("'port' key is not a valid port number")?,
+    };
+
+    let mut server = SERVER.lock().unwrap();
+    // The previous server must let go of its port before a new one can bind it
+    server.take();
+    let started = MetricsServer::bind(port).map_err(|e| e.to_string())?;
+    let port = started.local_addr().port();
+    *server = Some(started);
+    Ok(port)
+}
+
+#[no_mangle]
+/// Stops the server started by start_metrics_server
+pub extern "C" fn stop_metrics_server() -> *const c_char {
+    let output_json = match SERVER.lock().unwrap().take() {
+        Some(_) => json!({"success" : true}),
+        None => json!({"success" : false, "error" : "no metrics server is running"}),
+    };
+    CString::new(output_json.to_string()).unwrap().into_raw()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::system::test_util::call;
+
+    fn scrape(addr: SocketAddr, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    }
+
+    #[test]
+    fn scrape_reports_counters_and_cumulative_histograms() {
+        let system = call(ffi::create_jobsystem_with_config, json!({"workers" : 1}));
+        let system_id = &system["system_id"];
+        let job = call(
+            ffi::send_job,
+            json!({"system_id" : system_id, "type" : "print_success", "input" : {}}),
+        );
+        let result = call(
+            ffi::get_job,
+            json!({"handle_id" : job["handle_id"], "timeout_ms" : 5000}),
+        );
+        assert_eq!(result["success"], true);
+
+        let server = MetricsServer::bind(0).unwrap();
+        let (head, body) = scrape(server.local_addr(), "/metrics");
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(body
+            .lines()
+            .any(|line| line == "# TYPE job_system_jobs_total counter"));
+
+        // Buckets are cumulative, so the last one holds every recorded duration
+        let mut histograms = 0;
+        for line in body.lines().filter(|line| line.contains("_count{")) {
+            let (series, count) = line.rsplit_once(' ').unwrap();
+            let (name, labels) = series.split_once('{').unwrap();
+            let name = name.strip_suffix("_count").unwrap();
+            let labels = labels.strip_suffix('}').unwrap();
+            let infinite = format!("{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+            assert!(body.lines().any(|line| line == infinite), "{}", infinite);
+            histograms += 1;
+        }
+        assert!(histograms > 0);
+
+        call(ffi::destroy_jobsystem, json!({"system_id" : system_id}));
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let server = MetricsServer::bind(0).unwrap();
+        let (head, _) = scrape(server.local_addr(), "/nothing");
+        assert!(head.starts_with("HTTP/1.1 404 Not Found"));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use serde_json::{json, Value};
+
+/// Hashes `input` the same way no matter the order its objects were built in, and the same way across runs, so the
+/// hash can name files in an on-disk store
+pub fn canonical_hash(input: &Value) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hash_value(&mut hasher, input);
+    hasher.0
+}
+
+fn hash_value(hasher: &mut Fnv1a, value: &Value) {
+    // Every value starts with a tag, so e.g. the string "1" and the number 1 hash differently
+    match value {
+        Value::Null => hasher.write(b"n"),
+        Value::Bool(b) => hasher.write(if *b { b"t" } else { b"f" }),
+        Value::Number(n) => {
+            hasher.write(b"#");
+            hash_str(hasher, &n.to_string());
+        }
+        Value::String(s) => {
+            hasher.write(b"s");
+            hash_str(hasher, s);
+        }
+        Value::Array(values) => {
+            hasher.write(b"[");
+            hasher.write(&values.len().to_le_bytes());
+            values.iter().for_each(|v| hash_value(hasher, v));
+        }
+        Value::Object(map) => {
+            hasher.write(b"{");
+            hasher.write(&map.len().to_le_bytes());
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable_by_key(|(key, _)| *key);
+            for (key, value) in entries {
+                hash_str(hasher, key);
+                hash_value(hasher, value);
+            }
+        }
+    }
+}
+
+/// Length prefixed, so adjacent strings cannot run into each other
+fn hash_str(hasher: &mut Fnv1a, s: &str) {
+    hasher.write(&s.len().to_le_bytes());
+    hasher.write(s.as_bytes());
+}
+
+/// 64 bit FNV-1a, which unlike the standard library hashers is guaranteed to stay the same between releases
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    input: Value,
+    output: Value,
+    /// When the entry was last used, which orders it in `Entries::by_use`
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<(String, u64), Entry>,
+    /// Keys by the time they were last used, the least recently used first
+    by_use: BTreeMap<u64, (String, u64)>,
+    clock: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &(String, u64)) {
+        let Some(entry) = self.map.get_mut(key) else {
+            return;
+        };
+        self.by_use.remove(&entry.used);
+        self.clock += 1;
+        entry.used = self.clock;
+        self.by_use.insert(self.clock, key.clone());
+    }
+}
+
+/// Remembers the outputs of jobs by their job type and input, so a job sent again with the same input resolves
+/// without being run. Holds up to `capacity` results in memory, evicting the least recently used one to make room.
+/// With an on-disk store, every result is also written to a directory, where it outlives both eviction and the process
+#[derive(Debug)]
+pub struct ResultCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    store: Option<PathBuf>,
+}
+
+impl ResultCache {
+    /// A cache holding up to `capacity` results in memory, which is at least 1
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new(Entries::default()),
+            store: None,
+        }
+    }
+
+    /// Same as `new`, with results also kept as files in `dir`, which is created if it does not exist
+    pub fn with_store(capacity: usize, dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            store: Some(dir),
+            ..Self::new(capacity)
+        })
+    }
+
+    /// Number of results held in memory
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the output recorded for `input` under `job_type`, looking in the on-disk store if it is not in memory
+    pub fn get(&self, job_type: &str, input: &Value) -> Option<Value> {
+        let key = (job_type.to_string(), canonical_hash(input));
+        let mut entries = self.entries.lock().unwrap();
+        // Inputs are compared as well, so two inputs sharing a hash never see each other's output
+        if let Some(entry) = entries.map.get(&key).filter(|e| e.input == *input) {
+            let output = entry.output.clone();
+            entries.touch(&key);
+            return Some(output);
+        }
+        drop(entries);
+
+        let output = self.load(&key, input)?;
+        self.remember(key, input.clone(), output.clone());
+        Some(output)
+    }
+
+    /// Records `output` as the result of running a job of `job_type` on `input`
+    pub fn insert(&self, job_type: &str, input: &Value, output: &Value) {
+        let key = (job_type.to_string(), canonical_hash(input));
+        self.save(&key, input, output);
+        self.remember(key, input.clone(), output.clone());
+    }
+
+    fn remember(&self, key: (String, u64), input: Value, output: Value) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.map.remove(&key) {
+            entries.by_use.remove(&old.used);
+        }
+        while entries.map.len() >= self.capacity {
+            let Some((_, evicted)) = entries.by_use.pop_first() else {
+                break;
+            };
+            entries.map.remove(&evicted);
+        }
+        entries.clock += 1;
+        let used = entries.clock;
+        entries.by_use.insert(used, key.clone());
+        entries.map.insert(
+            key,
+            Entry {
+                input,
+                output,
+                used,
+            },
+        );
+    }
+
+    /// The file a result is stored in. The job type is hashed into the name, as it may hold any character
+    fn store_path(&self, (job_type, hash): &(String, u64)) -> Option<PathBuf> {
+        let mut hasher = Fnv1a(*hash);
+        hash_str(&mut hasher, job_type);
+        let dir = self.store.as_ref()?;
+        Some(dir.join(format!("{:016x}.json", hasher.0)))
+    }
+
+    fn load(&self, key: &(String, u64), input: &Value) -> Option<Value> {
+        let contents = fs::read_to_string(self.store_path(key)?).ok()?;
+        let stored: Value = serde_json::from_str(&contents).ok()?;
+        (stored["type"] == key.0.as_str() && stored["input"] == *input)
+            .then(|| stored["output"].clone())
+    }
+
+    /// A result that cannot be written is still cached in memory, it just does not outlive the process
+    fn save(&self, key: &(String, u64), input: &Value, output: &Value) {
+        let Some(path) = self.store_path(key) else {
+            return;
+        };
+        let stored = json!({"type" : key.0, "input" : input, "output" : output});
+        if let Err(e) = fs::write(&path, stored.to_string()) {
+            tracing::warn!("Failed to store cached result in {}: {}", path.display(), e);
+        }
+    }
+}
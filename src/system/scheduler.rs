@@ -0,0 +1,44 @@
+use std::fmt::Debug;
+
+use super::job_options::Priority;
+
+/// The queue jobs are sent to and workers serve when no other queue is named
+pub const DEFAULT_QUEUE: &str = "default";
+
+/// Selects how queued jobs are handed out to the workers of a `JobSystem`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scheduler {
+    /// Every worker receives from one queue behind one lock
+    #[default]
+    SharedQueue,
+    /// Every worker owns a queue, and idle workers steal from busy ones
+    WorkStealing,
+}
+
+/// The operations a worker pool needs from its queue, implemented by each `Scheduler`. Elements are sent to one of
+/// several named queues, and each worker only receives from the queues it subscribed to
+pub(crate) trait JobQueue<T: Send + Sync>: Debug + Send + Sync {
+    /// Called once per worker with the queues it serves, returning the index the worker passes to `recv`
+    fn register(&self, queues: &[String]) -> usize;
+
+    /// Whether a worker that was not asked to exit serves `queue`
+    fn serves(&self, queue: &str) -> bool;
+
+    fn send(&self, value: T, queue: &str, priority: Priority);
+
+    /// Sends every element of `values`, taking each lock once for the whole batch
+    fn send_batch(&self, values: Vec<T>, queue: &str, priority: Priority);
+
+    /// Takes the first element matching `predicate` out of the queue, if there is one
+    fn remove_where(&self, predicate: &dyn Fn(&T) -> bool) -> Option<T>;
+
+    /// Blocks until there is an element for `worker` in the queues it serves. Returns None once the worker was asked to join and no work is left
+    fn recv(&self, worker: usize) -> Option<T>;
+
+    /// Asks one worker to exit its loop once it runs out of work, never the last one serving a named queue. Returns
+    /// false if every worker left is needed
+    fn join_one(&self) -> bool;
+
+    /// Asks every worker to exit its loop once it runs out of work
+    fn join_all(&self);
+}
@@ -0,0 +1,49 @@
+use std::{
+    ffi::{c_char, CStr, CString},
+    future::{Future, IntoFuture},
+    path::PathBuf,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+use serde_json::Value;
+
+use super::job_system::ffi;
+
+/// Calls an FFI function with `input`, freeing the string it returns
+pub(crate) fn call(f: extern "C" fn(*const c_char) -> *const c_char, input: Value) -> Value {
+    let input = CString::new(input.to_string()).unwrap();
+    let output = f(input.as_ptr());
+    let value = serde_json::from_str(unsafe { CStr::from_ptr(output) }.to_str().unwrap());
+    ffi::free_str(output as *mut c_char);
+    value.unwrap()
+}
+
+/// A path in the temp directory that no other test or test run uses
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("job_system_{}_{}", std::process::id(), name))
+}
+
+/// Wakes the thread that is blocked in `block_on`
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls `future` to completion on the current thread, parking it while the future is pending
+pub(crate) fn block_on<F: IntoFuture>(future: F) -> F::Output {
+    let mut future = pin!(future.into_future());
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    fmt::{self, Debug},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Instant,
+};
+
+/// Why a task is being run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Fired {
+    /// The time the task was scheduled at has come
+    Due,
+    /// The timer was flushed before the task came due
+    Flushed,
+}
+
+impl Fired {
+    fn at(at: Instant) -> Self {
+        if Instant::now() < at {
+            Self::Flushed
+        } else {
+            Self::Due
+        }
+    }
+}
+
+pub(crate) type Task = Box<dyn FnOnce(Fired) + Send>;
+
+struct Entry {
+    at: Instant,
+    seq: u64,
+    task: Task,
+}
+
+/// Ordered so the BinaryHeap, which is a max-heap, pops the earliest entry first. Ties keep scheduling order
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.at.cmp(&self.at).then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+#[derive(Default)]
+struct State {
+    entries: BinaryHeap<Entry>,
+    next_seq: u64,
+    /// Once closed, tasks run as soon as they are scheduled
+    closed: bool,
+}
+
+/// Runs tasks once they come due on a single background thread, which is only started when the first task arrives
+pub(crate) struct Timer {
+    shared: Arc<(Mutex<State>, Condvar)>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Timer {
+    pub(crate) fn new() -> Self {
+        Self {
+            shared: Arc::new((Mutex::new(State::default()), Condvar::new())),
+            thread: Mutex::new(None),
+        }
+    }
+
+    /// Runs `task` on the timer thread at `at`, or earlier with `Fired::Flushed` if the timer is flushed first
+    pub(crate) fn schedule(&self, at: Instant, task: Task) {
+        let (state, due) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        if state.closed {
+            drop(state);
+            task(Fired::at(at));
+            return;
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.entries.push(Entry { at, seq, task });
+        due.notify_one();
+        drop(state);
+
+        let mut thread = self.thread.lock().unwrap();
+        if thread.is_none() {
+            let shared = self.shared.clone();
+            *thread = Some(thread::spawn(move || Self::timer_loop(&shared)));
+        }
+    }
+
+    /// Runs every pending task right away, and every task scheduled from now on as soon as it is scheduled
+    pub(crate) fn flush(&self) {
+        let (state, due) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.closed = true;
+        let entries = std::mem::take(&mut state.entries).into_sorted_vec();
+        due.notify_one();
+        drop(state);
+
+        // into_sorted_vec is ascending by Ord, which puts the earliest entry last
+        for entry in entries.into_iter().rev() {
+            (entry.task)(Fired::at(entry.at));
+        }
+        if let Some(handle) = self.thread.lock().unwrap().take() {
+            handle
+                .join()
+                .unwrap_or_else(|e| tracing::error!("Failed to join timer thread: {:?}", e));
+        }
+    }
+
+    fn timer_loop(shared: &(Mutex<State>, Condvar)) {
+        let (lock, due) = shared;
+        let mut state = lock.lock().unwrap();
+        while !state.closed {
+            let now = Instant::now();
+            match state.entries.peek().map(|e| e.at) {
+                Some(at) if at <= now => {
+                    let entry = state.entries.pop().unwrap();
+                    drop(state);
+                    (entry.task)(Fired::Due);
+                    state = lock.lock().unwrap();
+                }
+                Some(at) => state = due.wait_timeout(state, at - now).unwrap().0,
+                None => state = due.wait(state).unwrap(),
+            }
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.shared.0.lock().unwrap();
+        f.debug_struct("Timer")
+            .field("pending", &state.entries.len())
+            .field("closed", &state.closed)
+            .finish()
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Debug},
+    sync::{Arc, Mutex, Weak},
+};
+
+use super::{job_handle::HandleInner, job_options::Priority, worker::WorkerQueue};
+
+/// A job held back by the limit on its type, along with the queue and priority it is sent with once it gets a slot
+type Waiting<X, Y> = (Arc<HandleInner<X, Y>>, String, Priority);
+
+struct TypeSlots<X, Y> {
+    /// Jobs of the type that are queued or running
+    taken: usize,
+    waiting: VecDeque<Waiting<X, Y>>,
+}
+
+impl<X, Y> Default for TypeSlots<X, Y> {
+    fn default() -> Self {
+        Self {
+            taken: 0,
+            waiting: VecDeque::new(),
+        }
+    }
+}
+
+/// Caps how many jobs of a type are queued or running at once. Jobs past the cap are held in the pending state, where
+/// they still count against the capacity of the system, and queued in the order they were sent as the jobs ahead of
+/// them finish. Types without a limit pass straight through
+pub(crate) struct TypeLimits<X, Y> {
+    limits: HashMap<String, usize>,
+    queue: Weak<WorkerQueue<X, Y>>,
+    slots: Mutex<HashMap<String, TypeSlots<X, Y>>>,
+}
+
+impl<X: Send + Sync + 'static, Y: Send + Sync + 'static> TypeLimits<X, Y> {
+    pub(crate) fn new(limits: HashMap<String, usize>, queue: Weak<WorkerQueue<X, Y>>) -> Self {
+        Self {
+            limits,
+            queue,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Queues `inner`, or holds it back if its type is at its limit
+    pub(crate) fn send(
+        self: &Arc<Self>,
+        inner: Arc<HandleInner<X, Y>>,
+        queue: &str,
+        priority: Priority,
+    ) {
+        if !self.admit(&inner, queue, priority) {
+            return;
+        }
+        if let Some(message_queue) = self.queue.upgrade() {
+            message_queue.send(inner, queue, priority);
+        }
+    }
+
+    /// Same as `send` for a batch, queueing every job that gets a slot under a single lock
+    pub(crate) fn send_batch(
+        self: &Arc<Self>,
+        values: Vec<Arc<HandleInner<X, Y>>>,
+        queue: &str,
+        priority: Priority,
+    ) {
+        let admitted = values
+            .into_iter()
+            .filter(|inner| self.admit(inner, queue, priority))
+            .collect();
+        if let Some(message_queue) = self.queue.upgrade() {
+            message_queue.send_batch(admitted, queue, priority);
+        }
+    }
+
+    /// Takes every job that is held back, so they can be resolved without ever being queued
+    pub(crate) fn drain(&self) -> Vec<Arc<HandleInner<X, Y>>> {
+        let mut slots = self.slots.lock().unwrap();
+        slots
+            .values_mut()
+            .flat_map(|slots| slots.waiting.drain(..))
+            .map(|(inner, _, _)| inner)
+            .collect()
+    }
+
+    /// Takes a slot for the job if its type has one free, and holds the job back otherwise. Returns whether the job
+    /// may be queued
+    fn admit(
+        self: &Arc<Self>,
+        inner: &Arc<HandleInner<X, Y>>,
+        queue: &str,
+        priority: Priority,
+    ) -> bool {
+        let Some((job_type, &limit)) = inner
+            .job_type
+            .get()
+            .and_then(|job_type| self.limits.get_key_value(job_type))
+        else {
+            return true;
+        };
+        let mut slots = self.slots.lock().unwrap();
+        let type_slots = slots.entry(job_type.clone()).or_default();
+        if type_slots.taken >= limit {
+            inner.park();
+            type_slots
+                .waiting
+                .push_back((inner.clone(), queue.into(), priority));
+            return false;
+        }
+        type_slots.taken += 1;
+        drop(slots);
+        self.release_on_settled(inner, job_type.clone());
+        true
+    }
+
+    /// Gives the slot of the job back once it finishes and its function has returned, so a job left running past its
+    /// deadline still holds its slot. A job that is retried keeps its slot between attempts
+    fn release_on_settled(self: &Arc<Self>, inner: &HandleInner<X, Y>, job_type: String) {
+        let limits = Arc::downgrade(self);
+        inner.on_settled(Box::new(move || {
+            if let Some(limits) = limits.upgrade() {
+                limits.finished(&job_type);
+            }
+        }));
+    }
+
+    /// Hands the slot of a job of `job_type` that finished to the next job waiting for one, if any
+    fn finished(self: &Arc<Self>, job_type: &str) {
+        let next = {
+            let mut slots = self.slots.lock().unwrap();
+            let Some(type_slots) = slots.get_mut(job_type) else {
+                return;
+            };
+            // Jobs that were resolved while waiting, e.g. cancelled ones, give up their place
+            let next = std::iter::from_fn(|| type_slots.waiting.pop_front())
+                .find(|(inner, _, _)| inner.release());
+            if next.is_none() {
+                type_slots.taken -= 1;
+            }
+            next
+        };
+        let Some((inner, queue, priority)) = next else {
+            return;
+        };
+        self.release_on_settled(&inner, job_type.into());
+        if let Some(message_queue) = self.queue.upgrade() {
+            message_queue.send(inner, &queue, priority);
+        }
+    }
+}
+
+impl<X, Y> Debug for TypeLimits<X, Y> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypeLimits")
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use crate::system::{
+        job_handle::JobError,
+        job_options::JobOptions,
+        job_system::{JobSystem, JobSystemBuilder},
+    };
+
+    #[test]
+    fn held_back_jobs_count_against_the_capacity() {
+        let mut system: JobSystem<u32, u32> = JobSystemBuilder::new()
+            .workers(1)
+            .capacity(2)
+            .type_limit("make", 1)
+            .build();
+        let make = || JobOptions::new().job_type("make");
+        let (started, running) = mpsc::channel();
+        let (open, gate) = mpsc::channel::<()>();
+        let first = system.send_job_with(
+            0,
+            move |x| {
+                started.send(()).unwrap();
+                gate.recv().unwrap();
+                x
+            },
+            make(),
+        );
+        running.recv().unwrap();
+
+        let held: Vec<_> = (1..=2)
+            .map(|x| system.try_send_job_with(x, |x| x, make()).unwrap())
+            .collect();
+        assert!(system.try_send_job_with(3, |x| x, make()).is_err());
+
+        open.send(()).unwrap();
+        assert_eq!(first.get(), Ok(0));
+        let results: Vec<_> = held.into_iter().map(|handle| handle.get()).collect();
+        assert_eq!(results, [Ok(1), Ok(2)]);
+    }
+
+    #[test]
+    fn jobs_left_behind_by_their_deadline_keep_their_slot() {
+        let mut system: JobSystem<u32, bool> = JobSystemBuilder::new()
+            .workers(2)
+            .type_limit("make", 1)
+            .build();
+        let make = || JobOptions::new().job_type("make");
+        let first_running = Arc::new(AtomicBool::new(true));
+        let running = first_running.clone();
+        let first = system.send_job_with(
+            0,
+            move |_| {
+                thread::sleep(Duration::from_millis(300));
+                running.store(false, Ordering::SeqCst);
+                true
+            },
+            make().timeout(Duration::from_millis(50)),
+        );
+        assert_eq!(first.get(), Err(JobError::TimedOut));
+
+        // The first job is still running on its own thread, so this one has to wait for it to return
+        let running = first_running.clone();
+        let second = system.send_job_with(0, move |_| running.load(Ordering::SeqCst), make());
+        assert_eq!(second.get(), Ok(false));
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    cell::Cell,
+    fmt::{self, Debug},
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Condvar, Mutex, RwLock,
+    },
+};
+
+use super::{
+    job_options::Priority,
+    message_queue::{QueueSet, Roster},
+    scheduler::JobQueue,
+};
+
+thread_local! {
+    /// The queue and deque index of the worker running on this thread, so jobs sent from within a job stay local
+    static CURRENT_WORKER: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+    /// Round robin cursor for jobs sent from outside the pool, kept per thread so senders do not contend on it
+    static NEXT_DEQUE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A queue per worker. Workers serve their own deque first and steal from the others once it runs dry
+pub(crate) struct WorkStealingQueue<T>
+where
+    T: Send + Sync,
+{
+    deques: RwLock<Vec<Mutex<QueueSet<T>>>>,
+    /// The queues each worker serves, by worker index. Always locked before `deques` when both are needed, and never
+    /// held while locking `sleep`
+    roster: RwLock<Roster>,
+    /// Elements across every deque, used by idle workers to decide whether to sleep
+    queued: AtomicUsize,
+    sleepers: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+}
+
+impl<T: Send + Sync> WorkStealingQueue<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            // Jobs sent before the first worker registers wait in this deque, which the first worker then owns
+            deques: RwLock::new(vec![Mutex::new(QueueSet::new())]),
+            roster: RwLock::new(Roster::new()),
+            queued: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+        }
+    }
+
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Picks the deque for an element of `queue`: the sending worker's own deque if it serves the queue, otherwise the
+    /// deques of the workers serving it in turn. Elements of a queue nobody serves yet go to any deque, where the
+    /// workers that subscribe to it later steal them from
+    fn pick_deque(&self, queue: &str) -> usize {
+        let roster = self.roster.read().unwrap();
+        let serves = |worker: &usize| roster.serves_as(*worker, queue);
+        if let Some((id, worker)) = CURRENT_WORKER.get() {
+            if id == self.id() && serves(&worker) {
+                return worker;
+            }
+        }
+        let next = NEXT_DEQUE.replace(NEXT_DEQUE.get().wrapping_add(1));
+        let servers = (0..roster.len()).filter(serves).count();
+        (0..roster.len())
+            .filter(serves)
+            .nth(next % servers.max(1))
+            .unwrap_or(next % roster.len().max(1))
+    }
+
+    /// Pops from the worker's own deque, then tries every other deque in turn
+    fn find_work(&self, worker: usize) -> Option<T> {
+        let roster = self.roster.read().unwrap();
+        let deques = self.deques.read().unwrap();
+        let n = deques.len();
+        let value = (0..n)
+            .map(|offset| (worker + offset) % n)
+            .find_map(|i| deques[i].lock().unwrap().pop(roster.subscriptions(worker)))?;
+        self.queued.fetch_sub(1, SeqCst);
+        Some(value)
+    }
+
+    /// Whether any deque holds an element of a queue `worker` serves
+    fn has_work(&self, worker: usize) -> bool {
+        let roster = self.roster.read().unwrap();
+        let deques = self.deques.read().unwrap();
+        deques
+            .iter()
+            .any(|deque| deque.lock().unwrap().has_work(roster.subscriptions(worker)))
+    }
+
+    fn is_leaving(&self, worker: usize) -> bool {
+        self.roster.read().unwrap().is_leaving(worker)
+    }
+
+    /// While workers serve different queues, the worker woken might not serve the queue the element went to, so all
+    /// of them are woken instead
+    fn wake_one(&self) {
+        if self.sleepers.load(SeqCst) > 0 {
+            let uniform = self.roster.read().unwrap().uniform();
+            let _guard = self.sleep.lock().unwrap();
+            if uniform {
+                self.wake.notify_one();
+            } else {
+                self.wake.notify_all();
+            }
+        }
+    }
+
+    fn wake_all(&self) {
+        let _guard = self.sleep.lock().unwrap();
+        self.wake.notify_all();
+    }
+}
+
+impl<T: Send + Sync> JobQueue<T> for WorkStealingQueue<T> {
+    fn register(&self, queues: &[String]) -> usize {
+        let mut roster = self.roster.write().unwrap();
+        let index = roster.register(queues);
+        // A worker taking over the slot of one that exited also takes over its deque
+        let mut deques = self.deques.write().unwrap();
+        if index == deques.len() {
+            deques.push(Mutex::new(QueueSet::new()));
+        }
+        index
+    }
+
+    fn serves(&self, queue: &str) -> bool {
+        self.roster.read().unwrap().serves(queue)
+    }
+
+    fn send(&self, value: T, queue: &str, priority: Priority) {
+        // Counted before the push, so a worker popping the element right away never sees the count underflow
+        self.queued.fetch_add(1, SeqCst);
+        let index = self.pick_deque(queue);
+        {
+            let deques = self.deques.read().unwrap();
+            deques[index % deques.len()]
+                .lock()
+                .unwrap()
+                .push(value, queue, priority);
+        }
+        self.wake_one();
+    }
+
+    /// Splits the batch evenly across the deques of the workers serving `queue`, so idle workers find it without
+    /// stealing
+    fn send_batch(&self, values: Vec<T>, queue: &str, priority: Priority) {
+        let count = values.len();
+        self.queued.fetch_add(count, SeqCst);
+        {
+            let roster = self.roster.read().unwrap();
+            let deques = self.deques.read().unwrap();
+            let mut targets: Vec<usize> = (0..roster.len())
+                .filter(|&worker| roster.serves_as(worker, queue))
+                .collect();
+            if targets.is_empty() {
+                targets = (0..deques.len()).collect();
+            }
+            let chunk_size = count.div_ceil(targets.len()).max(1);
+            let mut values = values.into_iter();
+            for target in targets {
+                let mut deque = deques[target].lock().unwrap();
+                values
+                    .by_ref()
+                    .take(chunk_size)
+                    .for_each(|value| deque.push(value, queue, priority));
+            }
+        }
+        for _ in 0..count.min(self.sleepers.load(SeqCst)) {
+            self.wake_one();
+        }
+    }
+
+    fn remove_where(&self, predicate: &dyn Fn(&T) -> bool) -> Option<T> {
+        let deques = self.deques.read().unwrap();
+        let value = deques
+            .iter()
+            .find_map(|deque| deque.lock().unwrap().remove_where(predicate))?;
+        self.queued.fetch_sub(1, SeqCst);
+        Some(value)
+    }
+
+    fn recv(&self, worker: usize) -> Option<T> {
+        CURRENT_WORKER.set(Some((self.id(), worker)));
+        loop {
+            if let Some(value) = self.find_work(worker) {
+                return Some(value);
+            }
+            if self.is_leaving(worker) {
+                self.roster.write().unwrap().exited(worker);
+                return None;
+            }
+
+            // Register as a sleeper before re-checking, so a concurrent send either is seen here or notifies us
+            let guard = self.sleep.lock().unwrap();
+            self.sleepers.fetch_add(1, SeqCst);
+            let idle = self.queued.load(SeqCst) == 0 || !self.has_work(worker);
+            if idle && !self.is_leaving(worker) {
+                drop(self.wake.wait(guard).unwrap());
+            } else {
+                drop(guard);
+            }
+            self.sleepers.fetch_sub(1, SeqCst);
+        }
+    }
+
+    /// Every worker is woken, as the one asked to exit may be any of them
+    fn join_one(&self) -> bool {
+        let retired = self.roster.write().unwrap().retire_one();
+        if retired {
+            self.wake_all();
+        }
+        retired
+    }
+
+    fn join_all(&self) {
+        self.roster.write().unwrap().retire_all();
+        self.wake_all();
+    }
+}
+
+impl<T: Send + Sync> Debug for WorkStealingQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkStealingQueue")
+            .field("deques", &self.deques.read().unwrap().len())
+            .field("queued", &self.queued.load(SeqCst))
+            .field("roster", &*self.roster.read().unwrap())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+    };
+
+    use super::*;
+    use crate::system::scheduler::DEFAULT_QUEUE;
+
+    #[test]
+    fn workers_that_exited_hand_their_deques_on() {
+        let queue = Arc::new(WorkStealingQueue::<u32>::new());
+        let queues = [DEFAULT_QUEUE.to_string()];
+        for _ in 0..50 {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    let (queue, index) = (queue.clone(), queue.register(&queues));
+                    thread::spawn(move || while queue.recv(index).is_some() {})
+                })
+                .collect();
+            queue.join_all();
+            workers.into_iter().for_each(|w| w.join().unwrap());
+        }
+        assert_eq!(queue.deques.read().unwrap().len(), 4);
+        assert_eq!(queue.roster.read().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn sleeping_workers_wake_for_new_work_and_to_leave() {
+        let queue = Arc::new(WorkStealingQueue::<u32>::new());
+        let index = queue.register(&[DEFAULT_QUEUE.to_string()]);
+        let (sender, received) = mpsc::channel();
+        let worker = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                while let Some(value) = queue.recv(index) {
+                    sender.send(value).unwrap();
+                }
+            })
+        };
+        let wait_for_sleeper = || {
+            while queue.sleepers.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+        };
+
+        wait_for_sleeper();
+        queue.send(1, DEFAULT_QUEUE, 0);
+        assert_eq!(received.recv(), Ok(1));
+        wait_for_sleeper();
+        queue.join_all();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn idle_workers_steal_from_the_deques_of_busy_ones() {
+        let queue = WorkStealingQueue::<u32>::new();
+        let queues = [DEFAULT_QUEUE.to_string()];
+        let (busy, idle) = (queue.register(&queues), queue.register(&queues));
+        // Split evenly between the two deques
+        queue.send_batch(vec![1, 2, 3, 4], DEFAULT_QUEUE, 0);
+
+        let mut taken: Vec<_> = (0..4).map(|_| queue.recv(idle).unwrap()).collect();
+        taken.sort_unstable();
+        assert_eq!(taken, [1, 2, 3, 4]);
+        assert!(!queue.has_work(busy));
+    }
+}
@@ -1,59 +1,236 @@
-use std::{sync::Arc, thread};
+use std::{
+    any::Any,
+    fmt::{self, Debug},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
 
 use super::{
-    job_handle::{HandleInner, Status},
-    message_queue::MessageQueue,
+    job_context,
+    job_handle::{HandleInner, JobError, JobFn},
+    scheduler::JobQueue,
 };
 
-#[derive(Debug)]
-pub(crate) enum WorkerMessage<X, Y>
-where
-    X: Send + Sync,
-    Y: Send + Sync,
-{
-    Handle(Arc<HandleInner<X, Y>>),
+/// The queue a worker receives job handles from
+pub(crate) type WorkerQueue<X, Y> = dyn JobQueue<Arc<HandleInner<X, Y>>>;
+
+/// A job as seen by the system while a worker runs it, without its input and output types
+pub(crate) trait RunningJob: Send + Sync {
+    /// Sets the cancellation token of the job
+    fn cancel(&self);
 
-    /// Notifies the thread to stop accepting jobs and exit its worker loop
-    Join,
+    /// Resolves the job as cancelled without waiting for it to return
+    fn abandon(&self);
 }
 
-#[derive(Debug)]
+/// The job a worker is running, if any
+type CurrentJob = Mutex<Option<Arc<dyn RunningJob>>>;
+
 pub(crate) struct Worker {
     handle: Option<thread::JoinHandle<()>>,
+    current: Arc<CurrentJob>,
 }
 
 impl Worker {
     pub(crate) fn new<X: Send + Sync + 'static, Y: Send + Sync + 'static>(
-        message_receiver: Arc<MessageQueue<WorkerMessage<X, Y>>>,
+        message_receiver: Arc<WorkerQueue<X, Y>>,
+        queues: &[String],
     ) -> Self {
+        let index = message_receiver.register(queues);
+        let current = Arc::new(Mutex::new(None));
+        let slot = current.clone();
         Self {
-            handle: Some(thread::spawn(|| Self::worker_loop(message_receiver))),
+            handle: Some(thread::spawn(move || {
+                Self::supervise(message_receiver, index, &slot)
+            })),
+            current,
+        }
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.handle.as_ref().is_none_or(|h| h.is_finished())
+    }
+
+    /// Sets the cancellation token of the job the worker is running, if any
+    pub(crate) fn cancel_current(&self) {
+        if let Some(job) = self.current.lock().unwrap().as_ref() {
+            job.cancel();
         }
     }
 
-    fn worker_loop<X: Send + Sync, Y: Send + Sync>(
-        message_receiver: Arc<MessageQueue<WorkerMessage<X, Y>>>,
+    /// Resolves the job the worker is running as cancelled and lets go of the thread, which exits on its own once the
+    /// job returns
+    pub(crate) fn abandon(&mut self) {
+        if let Some(job) = self.current.lock().unwrap().take() {
+            job.abandon();
+        }
+        self.handle.take();
+    }
+
+    /// Keeps the worker slot alive: should the worker loop itself unwind, a fresh loop takes its place
+    fn supervise<X: Send + Sync + 'static, Y: Send + Sync + 'static>(
+        message_receiver: Arc<WorkerQueue<X, Y>>,
+        index: usize,
+        current: &CurrentJob,
+    ) {
+        while let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| {
+            Self::worker_loop(message_receiver.as_ref(), index, current)
+        })) {
+            tracing::error!(
+                worker = index,
+                "Worker loop panicked, restarting: {}",
+                panic_message(payload.as_ref())
+            );
+        }
+    }
+
+    fn worker_loop<X: Send + Sync + 'static, Y: Send + Sync + 'static>(
+        message_receiver: &WorkerQueue<X, Y>,
+        index: usize,
+        current: &CurrentJob,
     ) {
-        while let WorkerMessage::Handle(handle) = message_receiver.recv() {
-            if let Some(x) = handle.x.lock().unwrap().take() {
-                let func = handle.f;
-                *handle.status.lock().unwrap() = Status::Running;
-                let y = func(x);
-                let mut guarded_result = handle.result.lock().unwrap();
-                *guarded_result = Some(y);
-                *handle.status.lock().unwrap() = Status::Completed;
-                handle.available.notify_all();
+        while let Some(handle) = message_receiver.recv(index) {
+            // Jobs that were cancelled before a worker picked them up are skipped
+            if let Some((x, func)) = handle.start() {
+                *current.lock().unwrap() = Some(handle.clone());
+                let result = match handle.deadline.get() {
+                    None => run(&handle, x, func),
+                    Some(&deadline) => run_until(&handle, x, func, deadline),
+                };
+                current.lock().unwrap().take();
+                if !handle.retry(&result) {
+                    handle.complete(result);
+                }
             }
         }
     }
 }
 
+fn run<X, Y>(handle: &HandleInner<X, Y>, x: X, func: JobFn<X, Y>) -> Result<Y, JobError> {
+    let _span = handle.span.enter();
+    job_context::enter(handle.context());
+    // A panicking job must not take the worker thread down with it, or its handle would never resolve
+    let y = panic::catch_unwind(AssertUnwindSafe(|| func(x)));
+    job_context::exit();
+    match y {
+        Err(payload) => Err(JobError::Panicked(panic_message(payload.as_ref()))),
+        Ok(_) if handle.cancelled.load(Ordering::Relaxed) => Err(JobError::Cancelled),
+        Ok(y) => Ok(y),
+    }
+}
+
+/// Runs a job that has a deadline on a thread of its own. Should the deadline pass first, that thread is left behind
+/// with its cancellation token set, and the worker moves on to the next job, so a job that never returns does not
+/// cost the pool a worker
+fn run_until<X: Send + Sync + 'static, Y: Send + Sync + 'static>(
+    handle: &Arc<HandleInner<X, Y>>,
+    x: X,
+    func: JobFn<X, Y>,
+    deadline: Instant,
+) -> Result<Y, JobError> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(JobError::TimedOut);
+    }
+    let (sender, receiver) = mpsc::channel();
+    let job = handle.clone();
+    thread::spawn(move || {
+        let _ = sender.send(run(&job, x, func));
+    });
+    match receiver.recv_timeout(deadline - now) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => {
+            handle.cancelled.store(true, Ordering::Relaxed);
+            Err(JobError::TimedOut)
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(JobError::Panicked(
+            "job thread exited without a result".into(),
+        )),
+    }
+}
+
+/// Extracts the message passed to `panic!`, which is either a `&str` or a `String`
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".into()
+    }
+}
+
+impl Debug for Worker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Worker")
+            .field("handle", &self.handle)
+            .field("busy", &self.current.lock().unwrap().is_some())
+            .finish()
+    }
+}
+
 impl Drop for Worker {
     fn drop(&mut self) {
         if let Some(handle) = self.handle.take() {
             handle
                 .join()
-                .unwrap_or_else(|e| eprintln!("Failed to join thread: {:?}", e));
+                .unwrap_or_else(|e| tracing::error!("Failed to join thread: {:?}", e));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicBool, time::Duration};
+
+    use super::*;
+    use crate::system::{job_options::JobOptions, job_system::JobSystem};
+
+    #[test]
+    fn jobs_past_their_deadline_time_out_and_are_asked_to_stop() {
+        let mut system = JobSystem::new();
+        system.add_worker();
+        let (sender, returned) = mpsc::channel();
+        let slow = system.send_job_with(
+            0,
+            move |x: u32| {
+                thread::sleep(Duration::from_millis(200));
+                sender.send(job_context::is_cancelled()).unwrap();
+                x
+            },
+            JobOptions::new().timeout(Duration::from_millis(30)),
+        );
+        assert_eq!(slow.get(), Err(JobError::TimedOut));
+
+        // The worker moves on while the job that timed out is still running
+        let fast = system.send_job(1, |x| x);
+        assert_eq!(fast.get(), Ok(1));
+        assert!(returned.try_recv().is_err());
+        assert_eq!(returned.recv(), Ok(true));
+    }
+
+    #[test]
+    fn jobs_whose_deadline_passes_in_the_queue_never_run() {
+        let mut system = JobSystem::new();
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        let handle = system.send_job_with(
+            0,
+            move |x: u32| {
+                flag.store(true, Ordering::Relaxed);
+                x
+            },
+            JobOptions::new().timeout(Duration::from_millis(10)),
+        );
+        thread::sleep(Duration::from_millis(30));
+        system.add_worker();
+        assert_eq!(handle.get(), Err(JobError::TimedOut));
+        assert!(!ran.load(Ordering::Relaxed));
+    }
+}